@@ -1,3 +1,5 @@
+use serde_json::{Map, Value};
+
 pub type NameID = String;
 
 macro_rules! ast {
@@ -17,6 +19,18 @@ macro_rules! ast {
 					$(Self::$name($($id),*)=>StatementKind::$name),*
 				}
 			}
+
+			/// The statement as an object with its kind and its fields by name
+			pub fn to_json(&self) -> Value {
+				let mut map = Map::new();
+				match self {
+					$(Self::$name($($id),*)=>{
+						map.insert("kind".into(), stringify!($name).into());
+						$(map.insert(stringify!($id).into(), $id.clone().into());)*
+					}),*
+				}
+				Value::Object(map)
+			}
 		}
 
 		#[allow(dead_code)]
@@ -29,14 +43,14 @@ macro_rules! ast {
 
 ast! {
     USE(path: NameID, alias: NameID), // CHIP names available for creation could have other ids
-    IN(n: NameID),
-    OUT(n: NameID),
+    IN(name: NameID),
+    OUT(name: NameID),
 
-    RAIL(n: NameID),
+    RAIL(name: NameID),
 
     CHIP(alias: NameID, name: NameID),
 
     CONNECT(a: NameID, b: NameID),
 
-    CUSTOM(n: NameID)
+    CUSTOM(code: NameID)
 }
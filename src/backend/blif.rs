@@ -0,0 +1,134 @@
+pub struct BlifBackend;
+use super::netlist::{self, Netlist};
+use super::Backend;
+use crate::error::CompileError;
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+impl Backend for BlifBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        // The first model is the top one
+        let mut file = gen_model(&chip, &program)?;
+        for dep in netlist::dependencies(&chip, &program) {
+            write!(file, "\n{}", gen_model(dep, &program)?).unwrap();
+        }
+        Ok(file)
+    }
+}
+
+fn gen_model(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    let mut model = String::new();
+    writeln!(model, ".model {}", netlist::chip_ident(&chip.name)).unwrap();
+    if !chip.ins.is_empty() {
+        writeln!(model, ".inputs {}", chip.ins.join(" ")).unwrap();
+    }
+    if !chip.outs.is_empty() {
+        writeln!(model, ".outputs {}", chip.outs.join(" ")).unwrap();
+    }
+
+    if let Some(custom) = &netlist.custom {
+        let code =
+            get_custom_code(custom).ok_or_else(|| netlist::custom_error(chip, custom, "BLIF"))?;
+        writeln!(model, "{}", code).unwrap();
+        writeln!(model, ".end").unwrap();
+        return Ok(model);
+    }
+
+    for inst in &netlist.instances {
+        let pins = inst
+            .chip
+            .ins
+            .iter()
+            .map(|p| format!(" {}={}", p, net(&mut model, &netlist, &inst.pin(p))))
+            .chain(
+                inst.chip
+                    .outs
+                    .iter()
+                    .map(|p| format!(" {}={}", p, inst.pin(p))),
+            )
+            .collect::<String>();
+        writeln!(
+            model,
+            ".subckt {}{}",
+            netlist::chip_ident(&inst.chip.name),
+            pins
+        )
+        .unwrap();
+    }
+    // RAILs and OUTs have to be nets with their own name, so they're driven even by a single driver
+    for reader in netlist.rails.iter().chain(&chip.outs) {
+        gen_or(&mut model, &netlist.drivers_of(reader), reader);
+    }
+    writeln!(model, ".end").unwrap();
+    Ok(model)
+}
+
+/// Net with the value read by `reader`, the driver itself when there's only one
+/// or an OR of them in a net named like `reader` otherwise
+fn net(model: &mut String, netlist: &Netlist, reader: &str) -> String {
+    match netlist.drivers_of(reader).as_slice() {
+        [driver] => driver.to_string(),
+        drivers => {
+            gen_or(model, drivers, reader);
+            reader.into()
+        }
+    }
+}
+
+/// A `.names` table with the OR of `drivers`, which is 0 when there are none
+fn gen_or(model: &mut String, drivers: &[&str], output: &str) {
+    let mut names = drivers.to_vec();
+    names.push(output);
+    writeln!(model, ".names {}", names.join(" ")).unwrap();
+    for i in 0..drivers.len() {
+        let row = (0..drivers.len())
+            .map(|j| if i == j { '1' } else { '-' })
+            .collect::<String>();
+        writeln!(model, "{} 1", row).unwrap();
+    }
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some(".names i o\n0 1"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_a_model_of_subcircuits() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let blif = BlifBackend::compile(chip, program).unwrap();
+        assert!(blif.starts_with(
+            ".model xor\n.inputs in0 in1\n.outputs out\n\
+             .subckt STD_NOT i=in0 o=nt1.o\n.subckt STD_NOT i=in1 o=nt2.o\n\
+             .subckt STD_AND in0=in0 in1=nt2.o out=and1.out\n\
+             .subckt STD_AND in0=nt1.o in1=in1 out=and2.out\n\
+             .names and1.out and2.out out\n1- 1\n-1 1\n.end\n"
+        ));
+        assert!(blif.contains(".model STD_NOT\n.inputs i\n.outputs o\n.names i o\n0 1\n.end\n"));
+    }
+
+    #[test]
+    fn rails_are_or_tables_and_undriven_outs_zero() {
+        let (chip, program) = parse_chip(
+            "test",
+            "IN a\nIN b\nOUT o\nOUT z\nRAIL r\nCONNECT a r\nCONNECT b r\nCONNECT r o\n",
+        );
+        let blif = BlifBackend::compile(chip, program).unwrap();
+        assert_eq!(
+            blif,
+            ".model test\n.inputs a b\n.outputs o z\n\
+             .names a b r\n1- 1\n-1 1\n.names r o\n1 1\n.names z\n.end\n"
+        );
+    }
+}
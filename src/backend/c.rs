@@ -0,0 +1,200 @@
+pub struct CBackend;
+use super::netlist::{self, Netlist, Step};
+use super::Backend;
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+impl Backend for CBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut file = String::new();
+        writeln!(file, "#include \"{}\"\n", header_name(&chip)).unwrap();
+        // Only the top chip is exported, so chips sharing dependencies can be linked together
+        for dep in netlist::dependencies(&chip, &program) {
+            writeln!(file, "static {}", gen_function(dep, &program)?).unwrap();
+        }
+        write!(file, "{}", gen_function(&chip, &program)?).unwrap();
+        Ok(file)
+    }
+
+    fn extra_files(chip: &Chip, _program: &Program) -> Result<Vec<(String, String)>, CompileError> {
+        let guard = format!("{}_H", netlist::chip_ident(&chip.name).to_uppercase());
+        let mut header = String::new();
+        writeln!(header, "#ifndef {}", guard).unwrap();
+        writeln!(header, "#define {}\n", guard).unwrap();
+        writeln!(header, "#include <stdbool.h>\n").unwrap();
+        writeln!(header, "{};", gen_signature(chip)).unwrap();
+        writeln!(header, "\n#endif").unwrap();
+        Ok(vec![(header_name(chip), header)])
+    }
+}
+
+/// The header is named after the chip so the generated C file can include it
+fn header_name(chip: &Chip) -> String {
+    format!("{}.h", netlist::chip_ident(&chip.name))
+}
+
+/// Inputs are taken by value and outputs are written through pointers
+fn gen_signature(chip: &Chip) -> String {
+    let params = chip
+        .ins
+        .iter()
+        .map(|i| format!("bool {}", ident(i)))
+        .chain(chip.outs.iter().map(|o| format!("bool *{}", ident(o))))
+        .collect::<Vec<_>>();
+    format!(
+        "void {}({})",
+        ident(&netlist::chip_ident(&chip.name)),
+        if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        }
+    )
+}
+
+fn gen_function(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    netlist.check_idents("C", local)?;
+    let mut func = String::new();
+    writeln!(func, "{} {{", gen_signature(chip)).unwrap();
+
+    if let Some(custom) = &netlist.custom {
+        let code =
+            get_custom_code(custom).ok_or_else(|| netlist::custom_error(chip, custom, "C"))?;
+        writeln!(func, "{}", code).unwrap();
+        func += "}\n";
+        return Ok(func);
+    }
+
+    let order = netlist
+        .eval_order()
+        .ok_or_else(|| netlist.loop_error(|path| ErrorKind::Loop(chip.name.clone(), "C", path)))?;
+    for rail in &netlist.rails {
+        writeln!(func, "bool {};", ident(rail)).unwrap();
+    }
+    for inst in &netlist.instances {
+        for o in &inst.chip.outs {
+            writeln!(func, "bool {};", local(&inst.pin(o))).unwrap();
+        }
+    }
+    for step in order {
+        match step {
+            Step::Rail(r) => {
+                writeln!(func, "{} = {};", ident(&r), value(&netlist, &r)).unwrap();
+            }
+            Step::Instance(i) => {
+                let inst = &netlist.instances[i];
+                let args = inst
+                    .chip
+                    .ins
+                    .iter()
+                    .map(|p| value(&netlist, &inst.pin(p)))
+                    .chain(
+                        inst.chip
+                            .outs
+                            .iter()
+                            .map(|o| format!("&{}", local(&inst.pin(o)))),
+                    )
+                    .collect::<Vec<_>>();
+                writeln!(
+                    func,
+                    "{}({});",
+                    ident(&netlist::chip_ident(&inst.chip.name)),
+                    args.join(", ")
+                )
+                .unwrap();
+            }
+        }
+    }
+    for o in &chip.outs {
+        writeln!(func, "*{} = {};", ident(o), value(&netlist, o)).unwrap();
+    }
+    func += "}\n";
+    Ok(func)
+}
+
+fn value(netlist: &Netlist, reader: &str) -> String {
+    netlist.or_of(reader, local, " || ", "false")
+}
+
+/// Variable holding `a` or `chip.pin`
+fn local(name: &str) -> String {
+    ident(&name.replace('.', "_"))
+}
+
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+    "auto", "bool", "break", "case", "char", "const", "continue", "default", "do", "double",
+    "else", "enum", "extern", "false", "float", "for", "goto", "if", "inline", "int", "long",
+    "register", "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch",
+    "true", "typedef", "union", "unsigned", "void", "volatile", "while",
+];
+
+fn ident(name: &str) -> String {
+    netlist::ident(name, KEYWORDS)
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("*o = !i;"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AST;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_a_function_calling_static_dependencies() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let header = CBackend::extra_files(&chip, &program).unwrap();
+        assert_eq!(
+            header,
+            [(
+                "xor.h".to_string(),
+                "#ifndef XOR_H\n#define XOR_H\n\n#include <stdbool.h>\n\n\
+                 void xor(bool in0, bool in1, bool *out);\n\n#endif\n"
+                    .to_string()
+            )]
+        );
+        let c = CBackend::compile(chip, program).unwrap();
+        assert!(c.starts_with("#include \"xor.h\"\n\nstatic void STD_NOT(bool i, bool *o) {\n"));
+        assert!(c.contains("\nstatic void STD_AND(bool in0, bool in1, bool *out) {\n"));
+        let xor = &c[c.find("\nvoid xor(").unwrap() + 1..];
+        assert_eq!(
+            xor,
+            "void xor(bool in0, bool in1, bool *out) {\n\
+             bool nt1_o;\nbool nt2_o;\nbool and1_out;\nbool and2_out;\n\
+             STD_NOT(in0, &nt1_o);\nSTD_NOT(in1, &nt2_o);\n\
+             STD_AND(in0, nt2_o, &and1_out);\nSTD_AND(nt1_o, in1, &and2_out);\n\
+             *out = and1_out || and2_out;\n}\n"
+        );
+    }
+
+    #[test]
+    fn keywords_and_flattened_pins_are_different_variables() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN int\nOUT 1o\nCHIP not n\nCONNECT int n.i\nCONNECT n.o 1o\n",
+        );
+        let c = CBackend::compile(chip, program).unwrap();
+        assert!(c.contains(
+            "void test(bool int_, bool *_1o) {\nbool n_o;\nSTD_NOT(int_, &n_o);\n*_1o = n_o;\n}\n"
+        ));
+
+        let (mut chip, program) = parse_chip("test", "USE STD.NOT AS not\nCHIP not n\n");
+        chip.ast.push(AST::RAIL("n_o".into()));
+        let error = CBackend::compile(chip, program).unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::NameClash("n_o".into(), "n.o".into(), "C")
+        );
+    }
+}
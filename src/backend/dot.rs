@@ -0,0 +1,133 @@
+pub struct DotBackend;
+use super::netlist::{self, Netlist};
+use super::Backend;
+use crate::error::CompileError;
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+impl Backend for DotBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut file = String::new();
+        // One graph per chip, `dot -Tsvg -O` writes each one to its own file
+        for dep in netlist::dependencies(&chip, &program) {
+            writeln!(file, "{}", gen_graph(dep, &program)).unwrap();
+        }
+        write!(file, "{}", gen_graph(&chip, &program)).unwrap();
+        Ok(file)
+    }
+}
+
+fn gen_graph(chip: &Chip, program: &Program) -> String {
+    let netlist = Netlist::new(chip, program);
+    let mut graph = String::new();
+    writeln!(graph, "digraph {} {{", quote(&chip.name)).unwrap();
+    writeln!(graph, "rankdir=LR;").unwrap();
+    writeln!(graph, "label={};", quote(&chip.name)).unwrap();
+    for i in &chip.ins {
+        writeln!(graph, "{} [shape=invhouse];", quote(i)).unwrap();
+    }
+    for o in &chip.outs {
+        writeln!(graph, "{} [shape=house];", quote(o)).unwrap();
+    }
+    for rail in &netlist.rails {
+        writeln!(
+            graph,
+            "{} [shape=point, width=0.1, xlabel={}];",
+            quote(rail),
+            quote(rail)
+        )
+        .unwrap();
+    }
+    for inst in &netlist.instances {
+        writeln!(
+            graph,
+            "{} [shape=record, label=\"{{{}}}|{}\\n{}|{{{}}}\"];",
+            quote(&inst.name),
+            ports(&inst.chip.ins),
+            inst.name,
+            inst.chip.name,
+            ports(&inst.chip.outs)
+        )
+        .unwrap();
+    }
+    if let Some(custom) = &netlist.custom {
+        // Primitives have no wiring, the custom code sits between the INs and OUTs
+        let node = quote(&format!("CUSTOM {}", custom));
+        writeln!(graph, "{} [shape=box, style=dashed];", node).unwrap();
+        for i in &chip.ins {
+            writeln!(graph, "{} -> {};", quote(i), node).unwrap();
+        }
+        for o in &chip.outs {
+            writeln!(graph, "{} -> {};", node, quote(o)).unwrap();
+        }
+    }
+    for (reader, driver) in &netlist.connections {
+        writeln!(
+            graph,
+            "{} -> {};",
+            endpoint(driver, "e"),
+            endpoint(reader, "w")
+        )
+        .unwrap();
+    }
+    graph += "}\n";
+    graph
+}
+
+/// Record fields for a column of pins, each one being a port named like the pin
+fn ports(pins: &[String]) -> String {
+    pins.iter()
+        .map(|p| format!("<{0}> {0}", p))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// Node for `a`, or port of the instance node for `chip.pin` on the given side
+fn endpoint(name: &str, side: &str) -> String {
+    match name.split_once('.') {
+        Some((inst, pin)) => format!("{}:{}:{}", quote(inst), quote(pin), side),
+        None => quote(name),
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn one_graph_per_chip_with_pins_as_ports() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let dot = DotBackend::compile(chip, program).unwrap();
+        assert_eq!(dot.matches("digraph ").count(), 3);
+        assert!(dot.starts_with(
+            "digraph \"STD.NOT\" {\nrankdir=LR;\nlabel=\"STD.NOT\";\n\
+             \"i\" [shape=invhouse];\n\"o\" [shape=house];\n\
+             \"CUSTOM NOT\" [shape=box, style=dashed];\n\
+             \"i\" -> \"CUSTOM NOT\";\n\"CUSTOM NOT\" -> \"o\";\n}\n"
+        ));
+        let xor = &dot[dot.find("digraph \"xor\"").unwrap()..];
+        assert!(xor.contains(
+            "\"and1\" [shape=record, label=\"{<in0> in0|<in1> in1}|and1\\nSTD.AND|{<out> out}\"];\n"
+        ));
+        assert!(xor.contains("\"in0\" -> \"nt1\":\"i\":w;\n"));
+        assert!(xor.contains("\"nt1\":\"o\":e -> \"and2\":\"in0\":w;\n"));
+        assert!(xor.ends_with("\"and2\":\"out\":e -> \"out\";\n}\n"));
+    }
+
+    #[test]
+    fn rails_are_points() {
+        let (chip, program) = parse_chip("test", "IN a\nOUT o\nRAIL r\nCONNECT a r\nCONNECT r o\n");
+        let dot = DotBackend::compile(chip, program).unwrap();
+        assert!(dot.contains("\"r\" [shape=point, width=0.1, xlabel=\"r\"];\n"));
+        assert!(dot.contains("\"a\" -> \"r\";\n"));
+        assert!(dot.contains("\"r\" -> \"o\";\n"));
+    }
+}
@@ -1,6 +1,8 @@
 pub struct JsBackend;
+use super::netlist::{self, Netlist};
 use super::Backend;
 use crate::ast::AST;
+use crate::error::CompileError;
 use crate::lexer::{Chip, Program};
 
 use std::collections::HashMap;
@@ -13,33 +15,140 @@ enum ConnectionTree {
 }
 
 impl Backend for JsBackend {
-    fn compile(chip: Chip, program: Program) -> String {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
         let mut file = String::new();
-        writeln!(file, "{}", gen_class(chip.clone().name, chip, &program));
+        writeln!(file, "{}", gen_class(chip.clone().name, chip, &program)?).unwrap();
         for (k, v) in program.files.iter() {
-            writeln!(
-                file,
-                "{}",
-                gen_class(k.clone().replace(".", "_"), v.clone(), &program)
-            );
+            writeln!(file, "{}", gen_class(k.clone(), v.clone(), &program)?).unwrap();
         }
-        //println!("------------------- JS -------------------");
-        println!("{}", file);
-        file
+        Ok(file)
     }
 }
 
-fn gen_class(name: String, chip: Chip, program: &Program) -> String {
+fn gen_class(name: String, chip: Chip, program: &Program) -> Result<String, CompileError> {
     let mut file = String::new();
-    writeln!(file, "class {} {{", name);
-    writeln!(file, "{}", gen_run_code(chip, &program));
-    write!(file, "}}");
-    file
+    writeln!(file, "class {} {{", class(&name)).unwrap();
+    write!(file, "{}", gen_constructor(&chip, program)).unwrap();
+    writeln!(file, "{}", gen_run_code(chip, program)?).unwrap();
+    write!(file, "}}").unwrap();
+    Ok(file)
+}
+
+/// The sub-chips live as long as the chip so the ones with feedback loops keep their state,
+/// chips with loops themselves also keep the last value of every sub-chip output and RAIL
+fn gen_constructor(chip: &Chip, program: &Program) -> String {
+    let netlist = Netlist::new(chip, program);
+    if netlist.instances.is_empty() {
+        return String::new();
+    }
+    let mut func = String::new();
+    writeln!(func, "constructor(){{").unwrap();
+    let chips = netlist
+        .instances
+        .iter()
+        .map(|i| format!("{}: new {}()", ident(&i.name), class(&i.chip.name)))
+        .collect::<Vec<_>>();
+    writeln!(func, "this.chips = {{{}}};", chips.join(", ")).unwrap();
+    if netlist.eval_order().is_none() {
+        let state = netlist
+            .instances
+            .iter()
+            .map(|i| {
+                format!(
+                    "{}: [{}]",
+                    ident(&i.name),
+                    vec!["false"; i.chip.outs.len()].join(",")
+                )
+            })
+            .chain(netlist.rails.iter().map(|r| format!("{}: false", ident(r))))
+            .collect::<Vec<_>>();
+        writeln!(func, "this.state = {{{}}};", state.join(", ")).unwrap();
+    }
+    writeln!(func, "}}").unwrap();
+    func
+}
+
+/// Runs the sub-chips and updates the RAILs until nothing changes, for chips with feedback loops
+/// which can't be written as an expression per OUT. Each pass only depends on the state of the
+/// chip and its sub-chips before it, so if that state comes back the chip never settles.
+/// The locals start with a `$`, which names can't have
+fn gen_loop_code(chip: &Chip, netlist: &Netlist) -> String {
+    let mut func = String::new();
+    writeln!(func, "const $seen = new Set();").unwrap();
+    writeln!(func, "for (;;) {{").unwrap();
+    writeln!(func, "const $state = JSON.stringify(this);").unwrap();
+    writeln!(
+        func,
+        "if ($seen.has($state)) throw new Error(\"{} oscillates, its feedback loops never settle\");",
+        chip.name
+    )
+    .unwrap();
+    writeln!(func, "$seen.add($state);").unwrap();
+    writeln!(func, "let $changed = false;").unwrap();
+    writeln!(func, "let $next;").unwrap();
+    for r in &netlist.rails {
+        writeln!(func, "$next = !!({});", state_val(netlist, r)).unwrap();
+        writeln!(
+            func,
+            "if ($next !== this.state.{}) $changed = true;",
+            ident(r)
+        )
+        .unwrap();
+        writeln!(func, "this.state.{} = $next;", ident(r)).unwrap();
+    }
+    for inst in &netlist.instances {
+        let args = inst
+            .chip
+            .ins
+            .iter()
+            .map(|p| state_val(netlist, &inst.pin(p)))
+            .collect::<Vec<_>>();
+        let name = ident(&inst.name);
+        writeln!(func, "$next = {}.run({});", name, args.join(",")).unwrap();
+        writeln!(
+            func,
+            "if ($next.some(($v, $i) => $v !== this.state.{}[$i])) $changed = true;",
+            name
+        )
+        .unwrap();
+        writeln!(func, "this.state.{} = $next;", name).unwrap();
+    }
+    writeln!(func, "if (!$changed) break;").unwrap();
+    writeln!(func, "}}").unwrap();
+    for o in &chip.outs {
+        writeln!(func, "{} = {};", ident(o), state_val(netlist, o)).unwrap();
+    }
+    func
 }
 
-fn gen_run_code(chip: Chip, program: &Program) -> String {
+/// The OR of the drivers of `reader`, taking the sub-chip outputs and RAILs from the state
+fn state_val(netlist: &Netlist, reader: &str) -> String {
+    let driver = |d: &str| match d.split_once('.') {
+        Some((inst, pin)) => {
+            let chip = netlist.instance(inst).unwrap().chip;
+            let index = chip.outs.iter().position(|o| o == pin).unwrap();
+            format!("this.state.{}[{}]", ident(inst), index)
+        }
+        None if netlist.rails.iter().any(|r| r == d) => format!("this.state.{}", ident(d)),
+        None => ident(d),
+    };
+    netlist.or_of(reader, driver, "||", "false")
+}
+
+fn gen_run_code(chip: Chip, program: &Program) -> Result<String, CompileError> {
+    // Following the connections of a chip with loops would never end
+    let loop_code = {
+        let netlist = Netlist::new(&chip, program);
+        match netlist.eval_order() {
+            Some(_) => None,
+            None => Some(gen_loop_code(&chip, &netlist)),
+        }
+    };
     let mut func = String::new();
-    writeln!(func, "run({}){{", chip.ins.join(","));
+    let ins = chip.ins.iter().map(|i| ident(i)).collect::<Vec<_>>();
+    writeln!(func, "run({}){{", ins.join(",")).unwrap();
     let mut inputs = Vec::new();
     let mut outputs = Vec::new();
     let mut chip_aliases = HashMap::new();
@@ -49,10 +158,10 @@ fn gen_run_code(chip: Chip, program: &Program) -> String {
     let mut rails = Vec::new();
     let mut types = HashMap::new();
     let mut is_custom = false;
-    for statement in chip.ast {
+    for statement in chip.ast.clone() {
         match statement.clone() {
             AST::USE(p, n) => {
-                chip_aliases.insert(n.clone(), p.replace(".", "_"));
+                chip_aliases.insert(n.clone(), p.replace('.', "_"));
                 chip_aliases_v.insert(n, program.get_chip(&p));
             }
             AST::IN(n) => {
@@ -60,21 +169,23 @@ fn gen_run_code(chip: Chip, program: &Program) -> String {
                 types.insert(n, statement.as_kind());
             }
             AST::OUT(n) => {
-                writeln!(func, "let {} = false;", n);
+                writeln!(func, "let {} = false;", ident(&n)).unwrap();
                 inputs.push(n.clone());
                 types.insert(n, statement.as_kind());
             }
             AST::RAIL(n) => {
-                writeln!(func, "let {} = false;", n);
+                writeln!(func, "let {} = false;", ident(&n)).unwrap();
                 rails.push(n.clone());
                 types.insert(n, statement.as_kind());
             }
             AST::CUSTOM(n) => {
-                write!(func, "{}", get_custom_code(n));
+                let code =
+                    get_custom_code(&n).ok_or_else(|| netlist::custom_error(&chip, &n, "JS"))?;
+                write!(func, "{}", code).unwrap();
                 is_custom = true;
             }
             AST::CHIP(a, n) => {
-                writeln!(func, "let {} = new {}();", n, chip_aliases.get(&a).unwrap());
+                writeln!(func, "let {0} = this.chips.{0};", ident(&n)).unwrap();
                 for i in &chip_aliases_v.get(&a).unwrap().ins {
                     inputs.push(format!("{}.{}", n, i));
                 }
@@ -111,8 +222,9 @@ fn gen_run_code(chip: Chip, program: &Program) -> String {
             }
         }
     }
-    //println!("{:?}", connections);
-    if !is_custom {
+    if let Some(code) = loop_code {
+        func += &code;
+    } else if !is_custom {
         // ! BUILD connection tree
         let mut trees = Vec::new();
         for out in &chip.outs {
@@ -120,42 +232,44 @@ fn gen_run_code(chip: Chip, program: &Program) -> String {
         }
         for tree in trees {
             if let ConnectionTree::Regular(name, connected) = tree {
-                writeln!(func, "{} = {};", name, val(connected, &chip_defines));
+                writeln!(
+                    func,
+                    "{} = {};",
+                    ident(&name),
+                    val(connected, &chip_defines)
+                )
+                .unwrap();
             } else {
                 unreachable!("Code shouldn't be here, found a chip output")
             }
         }
     }
 
-    writeln!(func, "return [{}];", chip.outs.join(","));
+    let outs = chip.outs.iter().map(|o| ident(o)).collect::<Vec<_>>();
+    writeln!(func, "return [{}];", outs.join(",")).unwrap();
     func += "}";
-    func
+    Ok(func)
 }
 
-fn build_tree(start: String, connections: &Vec<(String, String)>) -> ConnectionTree {
-    if start.contains(".") {
+fn build_tree(start: String, connections: &[(String, String)]) -> ConnectionTree {
+    if start.contains('.') {
         let mut top: HashMap<String, Vec<ConnectionTree>> = HashMap::new();
         for (o, i) in connections {
-            if o.split(".").next().unwrap() == start.split(".").next().unwrap() && o != &start {
-                if top.contains_key(&o.clone()) {
-                    top.get_mut(&o.clone())
-                        .unwrap()
-                        .push(build_tree(i.clone(), &connections));
-                } else {
-                    top.insert(o.clone(), vec![build_tree(i.clone(), &connections)]);
-                }
+            if o.split('.').next().unwrap() == start.split('.').next().unwrap() && o != &start {
+                top.entry(o.clone())
+                    .or_default()
+                    .push(build_tree(i.clone(), connections));
             }
         }
-        return ConnectionTree::Chip(start, top);
+        ConnectionTree::Chip(start, top)
     } else {
         let mut top = Vec::new();
         for (o, i) in connections {
-            //println!("{} {}", o, start);
             if o == &start {
-                top.push(build_tree(i.clone(), &connections));
+                top.push(build_tree(i.clone(), connections));
             }
         }
-        return ConnectionTree::Regular(start, top);
+        ConnectionTree::Regular(start, top)
     }
 }
 
@@ -164,23 +278,27 @@ fn val(trees: Vec<ConnectionTree>, chip_aliases_v: &HashMap<String, &Chip>) -> S
     for tree in trees {
         match tree {
             ConnectionTree::Regular(name, children) => {
-                if children.len() > 0 {
+                if !children.is_empty() {
                     vals.push(format!("({})", val(children, chip_aliases_v)));
                 } else {
-                    vals.push(name);
+                    vals.push(ident(&name));
                 }
             }
             ConnectionTree::Chip(name, children) => {
-                let actual_name: String = name.split(".").next().unwrap().into();
+                let actual_name: String = name.split('.').next().unwrap().into();
                 let chip = chip_aliases_v.get(&actual_name).unwrap();
                 let mut args = Vec::new();
                 for i in &chip.ins {
                     let n = format!("{}.{}", actual_name, i);
-                    args.push(val(children.get(&n).unwrap().clone(), chip_aliases_v));
+                    // Inputs connected to nothing are false, like a RAIL without drivers
+                    args.push(match children.get(&n) {
+                        Some(c) => val(c.clone(), chip_aliases_v),
+                        None => "false".into(),
+                    });
                 }
                 vals.push(format!(
                     "{}.run({})[{}]",
-                    actual_name,
+                    ident(&actual_name),
                     args.join(","),
                     chip.outs
                         .iter()
@@ -190,14 +308,141 @@ fn val(trees: Vec<ConnectionTree>, chip_aliases_v: &HashMap<String, &Chip>) -> S
             }
         };
     }
-    vals.join("||")
+    // An OUT connected to nothing, like an input, is false
+    if vals.is_empty() {
+        "false".into()
+    } else {
+        vals.join("||")
+    }
+}
+
+/// Reserved words, which can't be class or local names in the strict mode of classes, and the
+/// globals the generated code uses
+const KEYWORDS: &[&str] = &[
+    "arguments",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "eval",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+    "Error",
+    "JSON",
+    "Set",
+];
+
+fn ident(name: &str) -> String {
+    netlist::ident(name, KEYWORDS)
+}
+
+/// Class of the chip `name`, which can be a path like `STD.NOT`
+fn class(name: &str) -> String {
+    ident(&netlist::chip_ident(name))
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("o = !i;\n"),
+        _ => None,
+    }
 }
 
-fn get_custom_code<'a>(n: String) -> &'a str {
-    match n.as_str() {
-        //"AND" => "out = in0 && in1;\n",
-        "NOT" => "o = !i;\n",
-        //"XOR" => "out = !!(in0 ^ in1)",
-        c => panic!("{} is not a valid custom code in JS", c),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn unconnected_outs_and_inputs_are_false() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN a\nOUT o\nOUT p\nCHIP not n\nCONNECT n.o p\n",
+        );
+        let js = JsBackend::compile(chip, program).unwrap();
+        assert!(js.starts_with(
+            "class test {\nconstructor(){\nthis.chips = {n: new STD_NOT()};\n}\n\
+             run(a){\nlet o = false;\nlet p = false;\nlet n = this.chips.n;\n\
+             o = false;\np = n.run(false)[0];\nreturn [o,p];\n}\n}\n"
+        ));
+    }
+
+    #[test]
+    fn loops_run_until_nothing_changes_or_a_state_comes_back() {
+        let (chip, program) = parse_chip("test", include_str!("../test.chip"));
+        let js = JsBackend::compile(chip, program).unwrap();
+        assert!(js.starts_with(
+            "class test {\nconstructor(){\n\
+             this.chips = {not1: new STD_NOT(), not2: new STD_NOT()};\n\
+             this.state = {not1: [false], not2: [false]};\n}\n\
+             run(q,r){\nlet o1 = false;\nlet o2 = false;\n\
+             let not1 = this.chips.not1;\nlet not2 = this.chips.not2;\n\
+             const $seen = new Set();\nfor (;;) {\nconst $state = JSON.stringify(this);\n\
+             if ($seen.has($state)) \
+             throw new Error(\"test oscillates, its feedback loops never settle\");\n\
+             $seen.add($state);\nlet $changed = false;\nlet $next;\n\
+             $next = not1.run(q||this.state.not2[0]);\n\
+             if ($next.some(($v, $i) => $v !== this.state.not1[$i])) $changed = true;\n\
+             this.state.not1 = $next;\n\
+             $next = not2.run(r||this.state.not1[0]);\n\
+             if ($next.some(($v, $i) => $v !== this.state.not2[$i])) $changed = true;\n\
+             this.state.not2 = $next;\nif (!$changed) break;\n}\n\
+             o1 = this.state.not1[0];\no2 = this.state.not2[0];\nreturn [o1,o2];\n}\n}\n"
+        ));
+    }
+
+    #[test]
+    fn keywords_and_globals_are_suffixed() {
+        let (chip, program) = parse_chip(
+            "class",
+            "USE STD.NOT AS not\nIN this\nIN 2\nOUT Set\nRAIL let\nCHIP not new\n\
+             CONNECT this let\nCONNECT 2 let\nCONNECT let new.i\nCONNECT new.o Set\n",
+        );
+        let js = JsBackend::compile(chip, program).unwrap();
+        assert!(js.starts_with(
+            "class class_ {\nconstructor(){\nthis.chips = {new_: new STD_NOT()};\n}\n\
+             run(this_,_2){\nlet Set_ = false;\nlet let_ = false;\n\
+             let new_ = this.chips.new_;\nSet_ = new_.run((this_||_2))[0];\nreturn [Set_];\n}\n}\n"
+        ));
     }
 }
@@ -0,0 +1,442 @@
+pub struct LogisimBackend;
+use super::netlist::{self, Netlist};
+use super::Backend;
+use crate::error::CompileError;
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+/// Left side of the column of components, everything is wired with tunnels so
+/// they're just stacked in it
+const COLUMN: i32 = 400;
+/// Length of the wires between a pin and its tunnel
+const STUB: i32 = 20;
+
+impl Backend for LogisimBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut file = String::new();
+        writeln!(
+            file,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>"
+        )
+        .unwrap();
+        writeln!(file, "<project source=\"3.8.0\" version=\"1.0\">").unwrap();
+        writeln!(file, "This file is intended to be loaded by Logisim-evolution (https://github.com/logisim-evolution/).").unwrap();
+        writeln!(file, "<lib desc=\"#Wiring\" name=\"0\"/>").unwrap();
+        writeln!(file, "<lib desc=\"#Gates\" name=\"1\"/>").unwrap();
+        writeln!(
+            file,
+            "<main name=\"{}\"/>",
+            escape(&netlist::chip_ident(&chip.name))
+        )
+        .unwrap();
+        let mut others = program
+            .files
+            .values()
+            .filter(|c| c.name != chip.name)
+            .collect::<Vec<_>>();
+        others.sort_by(|a, b| a.name.cmp(&b.name));
+        write!(file, "{}", gen_circuit(&chip, &program)?).unwrap();
+        for other in others {
+            write!(file, "{}", gen_circuit(other, &program)?).unwrap();
+        }
+        writeln!(file, "</project>").unwrap();
+        Ok(file)
+    }
+}
+
+/// A component facing east, with its pins relative to its location
+#[derive(Debug, Clone)]
+struct Component {
+    /// `None` for subcircuits
+    lib: Option<&'static str>,
+    name: String,
+    attrs: Vec<(&'static str, String)>,
+    ins: Vec<(i32, i32)>,
+    outs: Vec<(i32, i32)>,
+}
+
+impl Component {
+    fn gate(name: &str, inputs: usize) -> Self {
+        // Inputs of a 50 wide gate, the same way Logisim places them
+        let (start, dist, lower) = if inputs <= 3 {
+            (-10, 20, 20)
+        } else {
+            (-5, 10, 10)
+        };
+        let length = match name {
+            "NAND Gate" | "XOR Gate" => 60,
+            _ => 50,
+        };
+        let n = inputs as i32;
+        let ins = (0..n)
+            .map(|i| {
+                let dy = if n % 2 == 1 {
+                    start * (n - 1) + dist * i
+                } else if i >= n / 2 {
+                    start * n + dist * i + lower
+                } else {
+                    start * n + dist * i
+                };
+                (-length, dy)
+            })
+            .collect();
+        Self {
+            lib: Some("1"),
+            name: name.into(),
+            attrs: vec![("size", "50".into()), ("inputs", inputs.to_string())],
+            ins,
+            outs: vec![(0, 0)],
+        }
+    }
+
+    fn not() -> Self {
+        Self {
+            lib: Some("1"),
+            name: "NOT Gate".into(),
+            attrs: vec![("size", "30".into())],
+            ins: vec![(-30, 0)],
+            outs: vec![(0, 0)],
+        }
+    }
+
+    fn zero() -> Self {
+        Self {
+            lib: Some("0"),
+            name: "Constant".into(),
+            attrs: vec![("value", "0x0".into())],
+            ins: Vec::new(),
+            outs: vec![(0, 0)],
+        }
+    }
+
+    /// A subcircuit, with the pins of the appearance made by `gen_appearance`
+    fn chip(chip: &Chip, label: &str) -> Self {
+        let width = box_width(chip);
+        Self {
+            lib: None,
+            name: netlist::chip_ident(&chip.name),
+            attrs: vec![("label", label.into())],
+            ins: (0..chip.ins.len() as i32).map(|i| (0, 20 * i)).collect(),
+            outs: (0..chip.outs.len() as i32)
+                .map(|i| (width, 20 * i))
+                .collect(),
+        }
+    }
+
+    /// The STD chips are drawn with the gates built into Logisim
+    fn instance(chip: &Chip, label: &str) -> Self {
+        let mut component = match (chip.name.as_str(), chip.ins.len(), chip.outs.len()) {
+            ("STD.NOT", 1, 1) => Self::not(),
+            ("STD.AND", 2, 1) => Self::gate("AND Gate", 2),
+            ("STD.NAND", 2, 1) => Self::gate("NAND Gate", 2),
+            ("STD.XOR", 2, 1) => Self::gate("XOR Gate", 2),
+            _ => return Self::chip(chip, label),
+        };
+        component.attrs.push(("label", label.into()));
+        component
+    }
+}
+
+/// What's drawn in a circuit: components with the tunnel label of each of their pins
+#[derive(Debug, Clone, Default)]
+struct Circuit {
+    components: Vec<(Component, Vec<String>, Vec<String>)>,
+}
+
+impl Circuit {
+    fn add(&mut self, component: Component, ins: Vec<String>, outs: Vec<String>) {
+        self.components.push((component, ins, outs));
+    }
+
+    /// Label of the net with the value read by `reader`, adding an OR gate when it has many
+    /// drivers, as RAILs are wired-OR, or a 0 when it has none
+    fn reader(&mut self, netlist: &Netlist, reader: &str, visiting: &mut Vec<String>) -> String {
+        let drivers = netlist.drivers_of(reader);
+        if visiting.iter().any(|v| v == reader) {
+            return reader.into();
+        }
+        visiting.push(reader.into());
+        let label = match drivers.as_slice() {
+            [driver] => self.driver(netlist, driver, visiting),
+            [] => {
+                self.add(Component::zero(), Vec::new(), vec![reader.into()]);
+                reader.into()
+            }
+            _ => {
+                let ins = drivers
+                    .iter()
+                    .map(|d| self.driver(netlist, d, visiting))
+                    .collect::<Vec<_>>();
+                self.add(
+                    Component::gate("OR Gate", ins.len()),
+                    ins,
+                    vec![reader.into()],
+                );
+                reader.into()
+            }
+        };
+        visiting.pop();
+        label
+    }
+
+    /// RAILs take the label of whatever gives them their value
+    fn driver(&mut self, netlist: &Netlist, driver: &str, visiting: &mut Vec<String>) -> String {
+        if netlist.rails.iter().any(|r| r == driver) {
+            self.reader(netlist, driver, visiting)
+        } else {
+            driver.into()
+        }
+    }
+}
+
+fn gen_circuit(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    let mut circuit = Circuit::default();
+    let outs = match &netlist.custom {
+        Some(custom) => {
+            let component = get_custom_component(custom)
+                .ok_or_else(|| netlist::custom_error(chip, custom, "Logisim"))?;
+            circuit.add(component, chip.ins.clone(), chip.outs.clone());
+            chip.outs.clone()
+        }
+        None => {
+            for inst in &netlist.instances {
+                let ins = inst
+                    .chip
+                    .ins
+                    .iter()
+                    .map(|p| circuit.reader(&netlist, &inst.pin(p), &mut Vec::new()))
+                    .collect();
+                let outs = inst.chip.outs.iter().map(|p| inst.pin(p)).collect();
+                circuit.add(Component::instance(inst.chip, &inst.name), ins, outs);
+            }
+            chip.outs
+                .iter()
+                .map(|o| circuit.reader(&netlist, o, &mut Vec::new()))
+                .collect()
+        }
+    };
+
+    let name = netlist::chip_ident(&chip.name);
+    let mut xml = String::new();
+    writeln!(xml, "<circuit name=\"{}\">", escape(&name)).unwrap();
+    writeln!(xml, "<a name=\"circuit\" val=\"{}\"/>", escape(&name)).unwrap();
+    let ins = (0..chip.ins.len() as i32)
+        .map(|i| (100, 100 + 40 * i))
+        .collect::<Vec<_>>();
+    for (pin, (x, y)) in chip.ins.iter().zip(&ins) {
+        write!(
+            xml,
+            "{}",
+            comp(Some("0"), "Pin", (*x, *y), &[("label", pin.clone())])
+        )
+        .unwrap();
+        xml += &stub((*x, *y), STUB, pin);
+    }
+
+    let mut y = 100;
+    let mut right = COLUMN;
+    for (component, ins, outs) in &circuit.components {
+        let pins = component.ins.iter().chain(&component.outs);
+        let left = pins.clone().map(|p| p.0).min().unwrap();
+        let top = pins.clone().map(|p| p.1).min().unwrap() - 10;
+        let bottom = pins.map(|p| p.1).max().unwrap() + 10;
+        let loc = (COLUMN - left, y - top);
+        write!(
+            xml,
+            "{}",
+            comp(component.lib, &component.name, loc, &component.attrs)
+        )
+        .unwrap();
+        for ((dx, dy), label) in component.ins.iter().zip(ins) {
+            xml += &stub((loc.0 + dx, loc.1 + dy), -STUB, label);
+        }
+        for ((dx, dy), label) in component.outs.iter().zip(outs) {
+            xml += &stub((loc.0 + dx, loc.1 + dy), STUB, label);
+            right = right.max(loc.0 + dx);
+        }
+        y = loc.1 + bottom + 30;
+    }
+
+    let outs_at = (0..chip.outs.len() as i32)
+        .map(|i| (right + 200, 100 + 40 * i))
+        .collect::<Vec<_>>();
+    for ((pin, label), (x, y)) in chip.outs.iter().zip(&outs).zip(&outs_at) {
+        let attrs = [
+            ("facing", "west".to_string()),
+            ("output", "true".to_string()),
+            ("label", pin.clone()),
+        ];
+        write!(xml, "{}", comp(Some("0"), "Pin", (*x, *y), &attrs)).unwrap();
+        xml += &stub((*x, *y), -STUB, label);
+    }
+    xml += &gen_appearance(chip, &ins, &outs_at);
+    writeln!(xml, "</circuit>").unwrap();
+    Ok(xml)
+}
+
+/// A box with the inputs on the left and the outputs on the right, 20 apart,
+/// anchored at the first input
+fn gen_appearance(chip: &Chip, ins: &[(i32, i32)], outs: &[(i32, i32)]) -> String {
+    let width = box_width(chip);
+    let rows = chip.ins.len().max(chip.outs.len()).max(1) as i32;
+    let mut xml = String::new();
+    writeln!(xml, "<appear>").unwrap();
+    writeln!(
+        xml,
+        "<rect fill=\"none\" height=\"{}\" stroke=\"#000000\" stroke-width=\"2\" width=\"{}\" x=\"50\" y=\"50\"/>",
+        20 * rows,
+        width
+    )
+    .unwrap();
+    writeln!(
+        xml,
+        "<text dominant-baseline=\"central\" font-family=\"SansSerif\" font-size=\"12\" text-anchor=\"middle\" x=\"{}\" y=\"{}\">{}</text>",
+        50 + width / 2,
+        50 + 10 * rows,
+        escape(&chip.name)
+    )
+    .unwrap();
+    for (i, (x, y)) in ins.iter().enumerate() {
+        writeln!(
+            xml,
+            "<circ-port height=\"8\" pin=\"({},{})\" width=\"8\" x=\"46\" y=\"{}\"/>",
+            x,
+            y,
+            56 + 20 * i
+        )
+        .unwrap();
+    }
+    for (i, (x, y)) in outs.iter().enumerate() {
+        writeln!(
+            xml,
+            "<circ-port height=\"10\" pin=\"({},{})\" width=\"10\" x=\"{}\" y=\"{}\"/>",
+            x,
+            y,
+            45 + width,
+            55 + 20 * i
+        )
+        .unwrap();
+    }
+    writeln!(
+        xml,
+        "<circ-anchor facing=\"east\" height=\"6\" width=\"6\" x=\"47\" y=\"57\"/>"
+    )
+    .unwrap();
+    writeln!(xml, "</appear>").unwrap();
+    xml
+}
+
+/// Wide enough for the name of the chip, and on the grid
+fn box_width(chip: &Chip) -> i32 {
+    let text = 7 * chip.name.chars().count() as i32 + 20;
+    ((text + 9) / 10 * 10).max(40)
+}
+
+fn comp(
+    lib: Option<&str>,
+    name: &str,
+    (x, y): (i32, i32),
+    attrs: &[(&'static str, String)],
+) -> String {
+    let mut xml = String::new();
+    let lib = lib.map(|l| format!(" lib=\"{}\"", l)).unwrap_or_default();
+    writeln!(
+        xml,
+        "<comp{} loc=\"({},{})\" name=\"{}\">",
+        lib,
+        x,
+        y,
+        escape(name)
+    )
+    .unwrap();
+    for (k, v) in attrs {
+        writeln!(xml, "<a name=\"{}\" val=\"{}\"/>", k, escape(v)).unwrap();
+    }
+    writeln!(xml, "</comp>").unwrap();
+    xml
+}
+
+/// A wire from a pin at `from`, `length` to the right (or left if negative), ending in a tunnel
+fn stub(from: (i32, i32), length: i32, label: &str) -> String {
+    let to = (from.0 + length, from.1);
+    let mut xml = String::new();
+    writeln!(
+        xml,
+        "<wire from=\"({},{})\" to=\"({},{})\"/>",
+        from.0.min(to.0),
+        from.1,
+        from.0.max(to.0),
+        to.1
+    )
+    .unwrap();
+    // Tunnels point at the wire they're connected to
+    let facing = if length > 0 { "west" } else { "east" };
+    xml += &comp(
+        Some("0"),
+        "Tunnel",
+        to,
+        &[("facing", facing.into()), ("label", label.into())],
+    );
+    xml
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn get_custom_component(n: &str) -> Option<Component> {
+    match n {
+        "NOT" => Some(Component::not()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_the_main_circuit_with_not_gates_and_an_or() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let circ = LogisimBackend::compile(chip, program).unwrap();
+        assert!(circ.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n"));
+        assert!(circ.contains("<main name=\"xor\"/>\n<circuit name=\"xor\">\n"));
+        assert!(circ.ends_with("</circuit>\n</project>\n"));
+        let xor = &circ[circ.find("<circuit name=\"xor\">").unwrap()..];
+        let xor = &xor[..xor.find("</circuit>").unwrap()];
+        assert!(xor.contains(
+            "<comp lib=\"1\" loc=\"(430,110)\" name=\"NOT Gate\">\n\
+             <a name=\"size\" val=\"30\"/>\n<a name=\"label\" val=\"nt1\"/>\n</comp>\n"
+        ));
+        // Both ANDs drive the OUT
+        assert!(xor.contains(
+            "name=\"OR Gate\">\n<a name=\"size\" val=\"50\"/>\n<a name=\"inputs\" val=\"2\"/>\n"
+        ));
+        assert!(xor.contains("<a name=\"label\" val=\"and1.out\"/>"));
+        assert!(circ.contains("<circuit name=\"STD_AND\">\n"));
+    }
+
+    #[test]
+    fn rails_are_ors_and_undriven_outs_constants() {
+        let (chip, program) = parse_chip(
+            "test",
+            "IN a\nIN b\nOUT o\nOUT z\nRAIL r\nCONNECT a r\nCONNECT b r\nCONNECT r o\n",
+        );
+        let circ = LogisimBackend::compile(chip, program).unwrap();
+        let circ = &circ[..circ.find("</circuit>").unwrap()];
+        assert_eq!(circ.matches("name=\"OR Gate\"").count(), 1);
+        assert!(circ.contains(
+            "<comp lib=\"0\" loc=\"(470,130)\" name=\"Tunnel\">\n\
+             <a name=\"facing\" val=\"west\"/>\n<a name=\"label\" val=\"r\"/>\n"
+        ));
+        assert!(circ.contains("name=\"Constant\">\n<a name=\"value\" val=\"0x0\"/>\n</comp>\n"));
+    }
+}
@@ -0,0 +1,517 @@
+pub struct MinecraftBackend;
+use super::netlist::{self, Flat, Source};
+use super::Backend;
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+
+/// Minecraft 1.16.5, newer versions upgrade the blocks when pasting
+const DATA_VERSION: i32 = 2586;
+/// Rows taken by each lane: the lane, the tap repeater, the tap block and a free row
+const LANE: i32 = 4;
+/// Height of the wires running along X
+const LOWER: i32 = 1;
+/// Height of the wires running along Z, crossing over the lower ones
+const UPPER: i32 = 3;
+
+impl Backend for MinecraftBackend {
+    type Output = Vec<u8>;
+
+    fn compile(chip: Chip, program: Program) -> Result<Vec<u8>, CompileError> {
+        let flat = Flat::new(&chip, &program);
+        for primitive in &flat.primitives {
+            if primitive.custom != "NOT" {
+                return Err(netlist::custom_error(
+                    primitive.chip,
+                    &primitive.custom,
+                    "Minecraft",
+                ));
+            }
+        }
+        layout(&flat).schematic(&chip)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    North,
+    East,
+    South,
+    West,
+}
+
+const DIRS: [Dir; 4] = [Dir::North, Dir::East, Dir::South, Dir::West];
+
+impl Dir {
+    /// `(dx, dz)`, north is -Z
+    fn offset(self) -> (i32, i32) {
+        match self {
+            Dir::North => (0, -1),
+            Dir::East => (1, 0),
+            Dir::South => (0, 1),
+            Dir::West => (-1, 0),
+        }
+    }
+
+    fn opposite(self) -> Self {
+        match self {
+            Dir::North => Dir::South,
+            Dir::East => Dir::West,
+            Dir::South => Dir::North,
+            Dir::West => Dir::East,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Dir::North => "north",
+            Dir::East => "east",
+            Dir::South => "south",
+            Dir::West => "west",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Air,
+    Floor,
+    Stone,
+    Wire,
+    /// Sending the signal in that direction
+    Repeater(Dir),
+    /// On the side of a block, pointing in that direction
+    Torch(Dir),
+    Lever,
+    Lamp,
+}
+
+impl Block {
+    /// Solid blocks, which can be powered and carry dust on top
+    fn is_conductor(self) -> bool {
+        matches!(self, Block::Floor | Block::Stone | Block::Lamp)
+    }
+
+    /// Whether dust next to the block, in direction `dir`, turns towards it
+    fn connects(self, dir: Dir) -> bool {
+        match self {
+            Block::Wire | Block::Torch(_) | Block::Lever => true,
+            Block::Repeater(d) => d == dir || d == dir.opposite(),
+            _ => false,
+        }
+    }
+}
+
+/// The blocks of the schematic, anything not in it is air
+#[derive(Debug, Clone, Default)]
+struct World {
+    blocks: HashMap<(i32, i32, i32), Block>,
+}
+
+impl World {
+    fn get(&self, x: i32, y: i32, z: i32) -> Block {
+        self.blocks.get(&(x, y, z)).copied().unwrap_or(Block::Air)
+    }
+
+    fn set(&mut self, x: i32, y: i32, z: i32, block: Block) {
+        self.blocks.insert((x, y, z), block);
+    }
+
+    /// Places dust or a repeater with a block under it, the floor is added at the end
+    fn place(&mut self, x: i32, y: i32, z: i32, block: Block) {
+        self.set(x, y, z, block);
+        if y > 1 && self.get(x, y - 1, z) == Block::Air {
+            self.set(x, y - 1, z, Block::Stone);
+        }
+    }
+
+    /// Dust along Z at column `x`, with repeaters sending the signal towards `dir` on the rows
+    /// where `repeater` is true, and nothing on the rows in `holes`
+    fn column(
+        &mut self,
+        x: i32,
+        rows: std::ops::RangeInclusive<i32>,
+        dir: Dir,
+        repeater: impl Fn(i32) -> bool,
+        holes: &[i32],
+    ) {
+        for z in rows {
+            if holes.contains(&z) {
+                continue;
+            }
+            let block = if repeater(z) {
+                Block::Repeater(dir)
+            } else {
+                Block::Wire
+            };
+            self.place(x, UPPER, z, block);
+        }
+    }
+
+    /// Dust along X at row `z`, going away from `x` with a repeater on an odd column whenever
+    /// the signal gets weak. Odd columns are never crossed by a column of dust
+    fn lane(&mut self, z: i32, x: i32, strength: i32, last: i32) {
+        for (dir, range) in [
+            (Dir::West, (1..x).rev().collect::<Vec<_>>()),
+            (Dir::East, (x + 1..=last).collect()),
+        ] {
+            let mut strength = strength;
+            for x in range {
+                if x % 2 == 1 && strength <= 4 {
+                    self.place(x, LOWER, z, Block::Repeater(dir));
+                    strength = 16;
+                } else {
+                    self.place(x, LOWER, z, Block::Wire);
+                }
+                strength -= 1;
+            }
+        }
+    }
+
+    /// The sides dust connects to, the same way the game computes it
+    fn wire_state(&self, x: i32, y: i32, z: i32) -> String {
+        let covered = self.get(x, y + 1, z).is_conductor();
+        let mut sides = DIRS.map(|dir| {
+            let (dx, dz) = dir.offset();
+            let (nx, nz) = (x + dx, z + dz);
+            let next = self.get(nx, y, nz);
+            if next.connects(dir) {
+                "side"
+            } else if next.is_conductor() {
+                if !covered && self.get(nx, y + 1, nz) == Block::Wire {
+                    "up"
+                } else {
+                    "none"
+                }
+            } else if self.get(nx, y - 1, nz) == Block::Wire {
+                "side"
+            } else {
+                "none"
+            }
+        });
+        // Dust connected on a single side points both ways, and on none it's a cross
+        let connected = (0..4).filter(|i| sides[*i] != "none").collect::<Vec<_>>();
+        match connected.as_slice() {
+            [] => sides = ["side"; 4],
+            [i] => sides[(i + 2) % 4] = "side",
+            _ => {}
+        }
+        format!(
+            "minecraft:redstone_wire[east={},north={},power=0,south={},west={}]",
+            sides[1], sides[0], sides[2], sides[3]
+        )
+    }
+
+    fn state(&self, x: i32, y: i32, z: i32) -> String {
+        match self.get(x, y, z) {
+            Block::Air => "minecraft:air".into(),
+            Block::Floor => "minecraft:smooth_stone".into(),
+            Block::Stone => "minecraft:stone".into(),
+            Block::Wire => self.wire_state(x, y, z),
+            // The facing of a repeater is the side its input comes from
+            Block::Repeater(dir) => format!(
+                "minecraft:repeater[delay=1,facing={},locked=false,powered=false]",
+                dir.opposite().name()
+            ),
+            Block::Torch(dir) => {
+                format!(
+                    "minecraft:redstone_wall_torch[facing={},lit=true]",
+                    dir.name()
+                )
+            }
+            Block::Lever => "minecraft:lever[face=floor,facing=north,powered=false]".into(),
+            Block::Lamp => "minecraft:redstone_lamp[lit=false]".into(),
+        }
+    }
+
+    /// A Sponge schematic (version 2), gzipped NBT that WorldEdit can `//schem load`, for `chip`.
+    /// The sizes are shorts, so it fails for worlds over 32767 blocks on a side
+    fn schematic(&self, chip: &Chip) -> Result<Vec<u8>, CompileError> {
+        let size = |axis: fn(&(i32, i32, i32)) -> i32| {
+            let size = self.blocks.keys().map(axis).max().unwrap_or(0) + 1;
+            i16::try_from(size).map_err(|_| {
+                CompileError::new(
+                    ErrorKind::TooBig(chip.name.clone(), "Minecraft"),
+                    None,
+                    chip.file_name(),
+                )
+            })
+        };
+        let (width, height, length) = (size(|p| p.0)?, size(|p| p.1)?, size(|p| p.2)?);
+        let mut palette: Vec<String> = Vec::new();
+        let mut data = Vec::new();
+        // Indices go X first, then Z, then Y
+        for y in 0..height {
+            for z in 0..length {
+                for x in 0..width {
+                    let state = self.state(x.into(), y.into(), z.into());
+                    let index = match palette.iter().position(|s| *s == state) {
+                        Some(i) => i,
+                        None => {
+                            palette.push(state);
+                            palette.len() - 1
+                        }
+                    };
+                    write_varint(&mut data, index as u32);
+                }
+            }
+        }
+        let schematic = Tag::Compound(vec![
+            ("Version".into(), Tag::Int(2)),
+            ("DataVersion".into(), Tag::Int(DATA_VERSION)),
+            ("Width".into(), Tag::Short(width)),
+            ("Height".into(), Tag::Short(height)),
+            ("Length".into(), Tag::Short(length)),
+            ("PaletteMax".into(), Tag::Int(palette.len() as i32)),
+            (
+                "Palette".into(),
+                Tag::Compound(
+                    palette
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, s)| (s, Tag::Int(i as i32)))
+                        .collect(),
+                ),
+            ),
+            ("BlockData".into(), Tag::ByteArray(data)),
+            ("BlockEntities".into(), Tag::List(Vec::new())),
+        ]);
+        let mut nbt = Vec::new();
+        schematic.write_named(&mut nbt, "Schematic");
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&nbt).unwrap();
+        Ok(gz.finish().unwrap())
+    }
+}
+
+/// Lays out the flattened chip as redstone, seen from above with X to the right and Z down:
+///
+/// - Every IN and every NOT gets a lane, dust running along X at the lower level, one every
+///   `LANE` rows. The INs start at a lever on the west edge.
+/// - Every NOT and every OUT gets a column of dust running along Z at the upper level, crossing
+///   over the lanes. Where it reads from a lane, a repeater coming off the lane powers a block
+///   with dust on top, which climbs into the column, so the column is the OR of those lanes and
+///   the repeaters keep it from feeding back into them.
+/// - Columns end under the lanes, at a torch on a block for NOTs, whose output goes back up
+///   in the column next to it and steps down into the lane of the gate, or at a lamp for OUTs.
+///
+/// Gates go left to right in the order the chip uses them, so the ones from the same sub-chip
+/// stay together, and the OUTs go after them.
+fn layout(flat: &Flat) -> World {
+    let mut world = World::default();
+    let ins = flat.chip.ins.len();
+    let lane = |source: &Source| {
+        LANE * match source {
+            Source::In(i) => *i,
+            Source::Primitive(p, _) => ins + p,
+        } as i32
+    };
+    let lanes = (ins + flat.primitives.len()) as i32;
+    let bottom = LANE * lanes;
+    let gate_x = |p: usize| 2 + 4 * p as i32;
+    let out_x = |o: usize| gate_x(flat.primitives.len()) + 2 * o as i32;
+    let last = (out_x(flat.outs.len()) - 2).max(1);
+
+    // Columns reading from the lanes, with a repeater on every row of a lane to keep the signal up
+    let reader = |world: &mut World, x: i32, sources: &[Source]| {
+        let mut holes = Vec::new();
+        for source in sources {
+            let z = lane(source);
+            world.set(x, LOWER, z + 1, Block::Repeater(Dir::South));
+            world.set(x, LOWER, z + 2, Block::Stone);
+            world.set(x, LOWER + 1, z + 2, Block::Wire);
+            holes.push(z + 2);
+        }
+        world.column(
+            x,
+            0..=bottom,
+            Dir::South,
+            |z| z > 0 && z % LANE == 0,
+            &holes,
+        );
+    };
+    for (p, primitive) in flat.primitives.iter().enumerate() {
+        let x = gate_x(p);
+        reader(&mut world, x, &primitive.ins[0]);
+        world.set(x, UPPER, bottom + 1, Block::Stone);
+        world.set(x, UPPER, bottom + 2, Block::Torch(Dir::South));
+        // Back up into the lane of the gate, stepping down into it
+        let z = lane(&Source::Primitive(p, 0));
+        world.place(x + 1, UPPER, bottom + 2, Block::Wire);
+        world.column(
+            x + 2,
+            z + 2..=bottom + 2,
+            Dir::North,
+            |z| z % LANE == LANE - 1,
+            &[],
+        );
+        world.set(x + 2, LOWER, z + 1, Block::Stone);
+        world.set(x + 2, LOWER + 1, z + 1, Block::Wire);
+        // A repeater 3 blocks above the lane leaves it at 13
+        world.place(x + 2, LOWER, z, Block::Wire);
+        world.lane(z, x + 2, 13, last);
+    }
+    for (o, sources) in flat.outs.iter().enumerate() {
+        let x = out_x(o);
+        reader(&mut world, x, sources);
+        world.set(x, UPPER, bottom + 1, Block::Lamp);
+    }
+    for i in 0..ins {
+        let z = lane(&Source::In(i));
+        world.set(0, LOWER, z, Block::Lever);
+        world.lane(z, 0, 16, last);
+    }
+
+    let (width, length) = (last + 1, bottom + 3);
+    for x in 0..width {
+        for z in 0..length {
+            if world.get(x, 0, z) == Block::Air {
+                world.set(x, 0, z, Block::Floor);
+            }
+        }
+    }
+    world
+}
+
+/// The NBT tags used by schematics
+#[derive(Debug, Clone)]
+enum Tag {
+    Short(i16),
+    Int(i32),
+    ByteArray(Vec<u8>),
+    /// Only empty lists are needed
+    List(Vec<Tag>),
+    Compound(Vec<(String, Tag)>),
+}
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::ByteArray(_) => 7,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => 10,
+        }
+    }
+
+    fn write_named(&self, out: &mut Vec<u8>, name: &str) {
+        out.push(self.id());
+        write_string(out, name);
+        self.write(out);
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Tag::Short(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::Int(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::ByteArray(v) => {
+                out.extend_from_slice(&(v.len() as i32).to_be_bytes());
+                out.extend_from_slice(v);
+            }
+            Tag::List(v) => {
+                out.push(v.first().map_or(Tag::Compound(Vec::new()).id(), Tag::id));
+                out.extend_from_slice(&(v.len() as i32).to_be_bytes());
+                for tag in v {
+                    tag.write(out);
+                }
+            }
+            Tag::Compound(v) => {
+                for (name, tag) in v {
+                    tag.write_named(out, name);
+                }
+                out.push(0);
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        out.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AST;
+    use crate::lexer::parse_chip;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn unzip(schematic: &[u8]) -> Vec<u8> {
+        let mut nbt = Vec::new();
+        GzDecoder::new(schematic).read_to_end(&mut nbt).unwrap();
+        nbt
+    }
+
+    /// The value of the short tag `name`, read from the bytes after its header
+    fn short(nbt: &[u8], name: &str) -> i16 {
+        let mut header = vec![2];
+        write_string(&mut header, name);
+        let at = nbt.windows(header.len()).position(|w| w == header).unwrap() + header.len();
+        i16::from_be_bytes([nbt[at], nbt[at + 1]])
+    }
+
+    #[test]
+    fn schematic_sizes_cover_every_block() {
+        let (chip, _) = parse_chip("test", "IN a\nOUT o\nCONNECT a o\n");
+        let mut world = World::default();
+        world.set(0, 0, 0, Block::Floor);
+        world.set(2, 1, 3, Block::Lamp);
+        let nbt = unzip(&world.schematic(&chip).unwrap());
+        assert!(nbt.starts_with(b"\x0a\x00\x09Schematic"));
+        assert_eq!(short(&nbt, "Width"), 3);
+        assert_eq!(short(&nbt, "Height"), 2);
+        assert_eq!(short(&nbt, "Length"), 4);
+        let contains = |s: &str| nbt.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains("minecraft:air"));
+        assert!(contains("minecraft:smooth_stone"));
+        assert!(contains("minecraft:redstone_lamp[lit=false]"));
+    }
+
+    #[test]
+    fn worlds_wider_than_a_short_are_too_big() {
+        let (chip, _) = parse_chip("test", "IN a\nOUT o\nCONNECT a o\n");
+        let mut world = World::default();
+        world.set(i16::MAX.into(), 0, 0, Block::Floor);
+        let error = world.schematic(&chip).unwrap_err();
+        assert_eq!(*error.kind, ErrorKind::TooBig("test".into(), "Minecraft"));
+    }
+
+    #[test]
+    fn chips_are_built_from_levers_torches_and_lamps() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let nbt = unzip(&MinecraftBackend::compile(chip, program).unwrap());
+        let contains = |s: &str| nbt.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains("minecraft:lever["));
+        assert!(contains("minecraft:redstone_wall_torch["));
+        assert!(contains("minecraft:redstone_lamp["));
+    }
+
+    #[test]
+    fn only_not_can_be_built() {
+        let (chip, mut program) = parse_chip("test", "USE STD.NOT AS not\nCHIP not n\n");
+        let not = program.files.get_mut("STD.NOT").unwrap();
+        not.ast = vec![AST::CUSTOM("NAND".into())];
+        let error = MinecraftBackend::compile(chip, program).unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::InvalidCustom("NAND".into(), "Minecraft")
+        );
+    }
+}
@@ -1,7 +1,42 @@
+use crate::error::CompileError;
 use crate::lexer::{Chip, Program};
+mod blif;
+mod c;
+mod dot;
 mod js;
+mod logisim;
+mod minecraft;
+pub mod netlist;
+mod python;
+mod rust;
+mod svg;
+mod verilog;
+mod vhdl;
+mod yosys;
+pub use blif::BlifBackend;
+pub use c::CBackend;
+pub use dot::DotBackend;
 pub use js::JsBackend;
+pub use logisim::LogisimBackend;
+pub use minecraft::MinecraftBackend;
+pub use python::PythonBackend;
+pub use rust::RustBackend;
+pub use svg::SvgBackend;
+pub use verilog::VerilogBackend;
+pub use vhdl::VhdlBackend;
+pub use yosys::YosysBackend;
 
 pub trait Backend {
-    fn compile(chip: Chip, program: Program) -> String;
+    /// What `compile` generates, text for everything but binary formats
+    type Output: AsRef<[u8]>;
+
+    fn compile(chip: Chip, program: Program) -> Result<Self::Output, CompileError>;
+
+    /// Other files generated next to the main output, as `(file name, contents)`
+    fn extra_files(
+        _chip: &Chip,
+        _program: &Program,
+    ) -> Result<Vec<(String, String)>, CompileError> {
+        Ok(Vec::new())
+    }
 }
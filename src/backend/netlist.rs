@@ -0,0 +1,731 @@
+use crate::ast::AST;
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use std::collections::{HashMap, HashSet};
+
+/// A sub-chip created with `CHIP`
+#[derive(Debug, Clone)]
+pub struct Instance<'a> {
+    pub name: String,
+    pub chip: &'a Chip,
+}
+
+impl<'a> Instance<'a> {
+    pub fn pin(&self, pin: &str) -> String {
+        format!("{}.{}", self.name, pin)
+    }
+}
+
+/// The wiring of a chip, with every `CONNECT` oriented the same way `gen_run_code` does in the JS backend
+#[derive(Debug, Clone)]
+pub struct Netlist<'a> {
+    pub chip: &'a Chip,
+    pub rails: Vec<String>,
+    pub instances: Vec<Instance<'a>>,
+    /// `(reader, driver)` pairs, a reader with many drivers is the OR of all of them
+    pub connections: Vec<(String, String)>,
+    /// The primitive implemented by the chip, for chips like `STD.NOT`
+    pub custom: Option<String>,
+}
+
+impl<'a> Netlist<'a> {
+    pub fn new(chip: &'a Chip, program: &'a Program) -> Self {
+        let mut aliases = Vec::new();
+        let mut netlist = Self {
+            chip,
+            rails: Vec::new(),
+            instances: Vec::new(),
+            connections: Vec::new(),
+            custom: None,
+        };
+        for statement in &chip.ast {
+            match statement {
+                AST::USE(p, n) => aliases.push((n, program.get_chip(p))),
+                AST::RAIL(n) => netlist.rails.push(n.clone()),
+                AST::CHIP(a, n) => {
+                    let chip = aliases.iter().find(|(alias, _)| alias == &a).unwrap().1;
+                    netlist.instances.push(Instance {
+                        name: n.clone(),
+                        chip,
+                    });
+                }
+                AST::CONNECT(a, b) => {
+                    if netlist.is_reader(a) || netlist.is_driver(b) {
+                        netlist.connections.push((a.clone(), b.clone()));
+                    } else if netlist.is_reader(b) || netlist.is_driver(a) {
+                        netlist.connections.push((b.clone(), a.clone()));
+                    } else {
+                        unreachable!("LEXER FAILED: (a={}; b={})", a, b);
+                    }
+                }
+                AST::CUSTOM(n) => netlist.custom = Some(n.clone()),
+                AST::IN(_) | AST::OUT(_) => {}
+            }
+        }
+        netlist
+    }
+
+    pub fn instance(&self, name: &str) -> Option<&Instance<'a>> {
+        self.instances.iter().find(|i| i.name == name)
+    }
+
+    /// OUTs and inputs of the sub-chips
+    pub fn is_reader(&self, name: &str) -> bool {
+        match name.split_once('.') {
+            Some((inst, pin)) => self
+                .instance(inst)
+                .is_some_and(|i| i.chip.ins.iter().any(|x| x == pin)),
+            None => self.chip.outs.iter().any(|x| x == name),
+        }
+    }
+
+    /// INs and outputs of the sub-chips
+    pub fn is_driver(&self, name: &str) -> bool {
+        match name.split_once('.') {
+            Some((inst, pin)) => self
+                .instance(inst)
+                .is_some_and(|i| i.chip.outs.iter().any(|x| x == pin)),
+            None => self.chip.ins.iter().any(|x| x == name),
+        }
+    }
+
+    /// Everything that gets its value from CONNECTs: OUTs, RAILs and the inputs of the sub-chips
+    pub fn readers(&self) -> Vec<String> {
+        let mut readers = self.chip.outs.clone();
+        readers.extend(self.rails.iter().cloned());
+        for inst in &self.instances {
+            readers.extend(inst.chip.ins.iter().map(|i| inst.pin(i)));
+        }
+        readers
+    }
+
+    pub fn drivers_of(&self, reader: &str) -> Vec<&str> {
+        self.connections
+            .iter()
+            .filter(|(r, _)| r == reader)
+            .map(|(_, d)| d.as_str())
+            .collect()
+    }
+
+    /// Checks that `ident` gives a different identifier to every IN, OUT, RAIL, sub-chip and
+    /// sub-chip pin, as backends write `chip.pin` as a single name
+    pub fn check_idents(
+        &self,
+        backend: &'static str,
+        ident: impl Fn(&str) -> String,
+    ) -> Result<(), CompileError> {
+        let pins = self.instances.iter().flat_map(|inst| {
+            inst.chip
+                .ins
+                .iter()
+                .chain(&inst.chip.outs)
+                .map(move |p| inst.pin(p))
+        });
+        let names = self
+            .chip
+            .ins
+            .iter()
+            .chain(&self.chip.outs)
+            .chain(&self.rails)
+            .cloned()
+            .chain(self.instances.iter().map(|inst| inst.name.clone()))
+            .chain(pins);
+        let mut seen = HashMap::new();
+        for name in names {
+            if let Some(first) = seen.insert(ident(&name), name.clone()) {
+                return Err(CompileError::new(
+                    ErrorKind::NameClash(first, name, backend),
+                    None,
+                    self.chip.file_name(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Expression for the value of `reader`, RAILs are wired-OR so it joins every driver
+    /// formatted by `driver` with `or`, and it's `empty` when nothing drives it
+    pub fn or_of(
+        &self,
+        reader: &str,
+        driver: impl Fn(&str) -> String,
+        or: &str,
+        empty: &str,
+    ) -> String {
+        let drivers = self.drivers_of(reader);
+        if drivers.is_empty() {
+            empty.into()
+        } else {
+            drivers.into_iter().map(driver).collect::<Vec<_>>().join(or)
+        }
+    }
+
+    /// The step that computes the value of a driver, `None` for INs
+    fn step_of(&self, driver: &str) -> Option<Step> {
+        match driver.split_once('.') {
+            Some((inst, _)) => self
+                .instances
+                .iter()
+                .position(|i| i.name == inst)
+                .map(Step::Instance),
+            None if self.rails.iter().any(|r| r == driver) => Some(Step::Rail(driver.into())),
+            None => None,
+        }
+    }
+
+    /// Orders the RAILs and sub-chips so every step comes after the ones it reads from,
+    /// `None` if there's a feedback loop
+    pub fn eval_order(&self) -> Option<Vec<Step>> {
+        fn visit(
+            netlist: &Netlist,
+            step: Step,
+            visiting: &mut Vec<Step>,
+            order: &mut Vec<Step>,
+        ) -> Option<()> {
+            if order.contains(&step) {
+                return Some(());
+            }
+            if visiting.contains(&step) {
+                return None;
+            }
+            let readers = match &step {
+                Step::Rail(r) => vec![r.clone()],
+                Step::Instance(i) => {
+                    let inst = &netlist.instances[*i];
+                    inst.chip.ins.iter().map(|p| inst.pin(p)).collect()
+                }
+            };
+            visiting.push(step.clone());
+            for reader in readers {
+                for driver in netlist.drivers_of(&reader) {
+                    if let Some(dep) = netlist.step_of(driver) {
+                        visit(netlist, dep, visiting, order)?;
+                    }
+                }
+            }
+            visiting.pop();
+            order.push(step);
+            Some(())
+        }
+        let mut order = Vec::new();
+        let steps = self
+            .rails
+            .iter()
+            .map(|r| Step::Rail(r.clone()))
+            .chain((0..self.instances.len()).map(Step::Instance));
+        for step in steps {
+            visit(self, step, &mut Vec::new(), &mut order)?;
+        }
+        Some(order)
+    }
+
+    /// Signals that get their value from `signal`: what it's CONNECTed to and,
+    /// for an input of a sub-chip, all the outputs of the sub-chip
+    fn fed_by(&self, signal: &str) -> Vec<String> {
+        let mut fed = self
+            .connections
+            .iter()
+            .filter(|(_, d)| d == signal)
+            .map(|(r, _)| r.clone())
+            .collect::<Vec<_>>();
+        if let Some((inst, pin)) = signal.split_once('.') {
+            if let Some(inst) = self.instance(inst) {
+                if inst.chip.ins.iter().any(|i| i == pin) {
+                    fed.extend(inst.chip.outs.iter().map(|o| inst.pin(o)));
+                }
+            }
+        }
+        fed
+    }
+
+    /// A feedback loop as the signals it goes through, each one feeding the next. It starts
+    /// at the output of a sub-chip and ends at an input of the same sub-chip, `None` if there's none
+    pub fn find_loop(&self) -> Option<Vec<String>> {
+        fn visit(
+            netlist: &Netlist,
+            signal: String,
+            path: &mut Vec<String>,
+            done: &mut HashSet<String>,
+        ) -> Option<Vec<String>> {
+            if let Some(start) = path.iter().position(|s| *s == signal) {
+                return Some(path[start..].to_vec());
+            }
+            if done.contains(&signal) {
+                return None;
+            }
+            path.push(signal.clone());
+            for next in netlist.fed_by(&signal) {
+                if let Some(found) = visit(netlist, next, path, done) {
+                    return Some(found);
+                }
+            }
+            path.pop();
+            done.insert(signal);
+            None
+        }
+        let mut done = HashSet::new();
+        for inst in &self.instances {
+            for o in &inst.chip.outs {
+                if let Some(mut found) = visit(self, inst.pin(o), &mut Vec::new(), &mut done) {
+                    // RAILs can't be CONNECTed to each other, so every loop goes through a sub-chip
+                    let start = found
+                        .iter()
+                        .position(|s| s.contains('.') && self.is_driver(s))
+                        .unwrap();
+                    found.rotate_left(start);
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+
+    /// Error for a chip with a feedback loop, pointing at the CONNECTs that make it.
+    /// `kind` gets the loop found by `find_loop`
+    pub fn loop_error(&self, kind: impl FnOnce(Vec<String>) -> ErrorKind) -> CompileError {
+        let path = self.find_loop().unwrap_or_default();
+        let mut spans = Vec::new();
+        for (i, a) in path.iter().enumerate() {
+            let b = &path[(i + 1) % path.len()];
+            let connects = &self.chip.connect_spans;
+            if let Some(span) = connects
+                .get(&(a.clone(), b.clone()))
+                .or_else(|| connects.get(&(b.clone(), a.clone())))
+            {
+                spans.push((*span, format!("{} -> {}", a, b)));
+            }
+        }
+        let mut spans = spans.into_iter();
+        let mut error = CompileError::new(
+            kind(path),
+            spans.next().map(|(span, _)| span),
+            self.chip.file_name(),
+        );
+        for (span, message) in spans {
+            error = error.with_label(span, message);
+        }
+        error
+    }
+}
+
+/// Something computed inside a chip, see `Netlist::eval_order`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Rail(String),
+    /// Index into `Netlist::instances`
+    Instance(usize),
+}
+
+/// A signal of a flattened chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Index into the INs of the top chip
+    In(usize),
+    /// Output of a primitive, as `(primitive, pin)`
+    Primitive(usize, usize),
+}
+
+/// An instance of a CUSTOM chip, anywhere in the hierarchy
+#[derive(Debug, Clone)]
+pub struct Primitive<'a> {
+    pub chip: &'a Chip,
+    pub custom: String,
+    /// Names of the instances from the top chip down to this one, joined with `.`,
+    /// empty if the top chip is the primitive
+    pub path: String,
+    /// What drives each input, which gets the OR of all of them
+    pub ins: Vec<Vec<Source>>,
+}
+
+/// A chip flattened down to its CUSTOM primitives, with the RAILs and the pins
+/// of the sub-chips in between resolved
+#[derive(Debug, Clone)]
+pub struct Flat<'a> {
+    pub chip: &'a Chip,
+    pub primitives: Vec<Primitive<'a>>,
+    /// What drives each OUT
+    pub outs: Vec<Vec<Source>>,
+    /// Every chip in the hierarchy, the top one first, empty if the top chip is a primitive
+    scopes: Vec<Scope<'a>>,
+}
+
+/// A signal anywhere in the hierarchy, see `Flat::probes`
+#[derive(Debug, Clone)]
+pub struct Probe {
+    /// Names of the instances from the top chip down to the one with the signal
+    pub scope: Vec<String>,
+    pub name: String,
+    /// What drives it, the signal is the OR of all of them
+    pub sources: Vec<Source>,
+}
+
+/// A chip somewhere in the hierarchy while flattening
+#[derive(Debug, Clone)]
+struct Scope<'a> {
+    netlist: Netlist<'a>,
+    /// The scope containing this one and the name of the instance
+    parent: Option<(usize, String)>,
+    children: HashMap<String, Child>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Child {
+    Primitive(usize),
+    Scope(usize),
+}
+
+impl<'a> Flat<'a> {
+    pub fn new(chip: &'a Chip, program: &'a Program) -> Self {
+        let netlist = Netlist::new(chip, program);
+        if let Some(custom) = netlist.custom {
+            // The top chip is a primitive itself
+            return Self {
+                chip,
+                primitives: vec![Primitive {
+                    chip,
+                    custom,
+                    path: String::new(),
+                    ins: (0..chip.ins.len()).map(|i| vec![Source::In(i)]).collect(),
+                }],
+                outs: (0..chip.outs.len())
+                    .map(|o| vec![Source::Primitive(0, o)])
+                    .collect(),
+                scopes: Vec::new(),
+            };
+        }
+        let mut scopes = Vec::new();
+        let mut primitives = Vec::new();
+        let mut owners = Vec::new();
+        expand(
+            netlist,
+            None,
+            program,
+            &mut scopes,
+            &mut primitives,
+            &mut owners,
+        );
+        for (primitive, (scope, inst)) in primitives.iter_mut().zip(owners) {
+            primitive.ins = primitive
+                .chip
+                .ins
+                .iter()
+                .map(|pin| {
+                    resolve(
+                        &scopes,
+                        scope,
+                        &format!("{}.{}", inst, pin),
+                        &mut Vec::new(),
+                    )
+                })
+                .collect();
+        }
+        let outs = chip
+            .outs
+            .iter()
+            .map(|o| resolve(&scopes, 0, o, &mut Vec::new()))
+            .collect();
+        Self {
+            chip,
+            primitives,
+            outs,
+            scopes,
+        }
+    }
+
+    /// The INs, OUTs and RAILs of the top chip and of every chip inside it, each chip
+    /// right before the ones it contains
+    pub fn probes(&self) -> Vec<Probe> {
+        let mut probes = Vec::new();
+        if self.scopes.is_empty() {
+            self.primitive_probes(0, &[], &mut probes);
+        } else {
+            self.scope_probes(0, &mut Vec::new(), &mut probes);
+        }
+        probes
+    }
+
+    fn scope_probes(&self, scope: usize, path: &mut Vec<String>, probes: &mut Vec<Probe>) {
+        let netlist = &self.scopes[scope].netlist;
+        let mut probe = |name: &String, sources| {
+            probes.push(Probe {
+                scope: path.clone(),
+                name: name.clone(),
+                sources,
+            })
+        };
+        for (i, name) in netlist.chip.ins.iter().enumerate() {
+            probe(
+                name,
+                match &self.scopes[scope].parent {
+                    Some((parent, inst)) => resolve(
+                        &self.scopes,
+                        *parent,
+                        &format!("{}.{}", inst, name),
+                        &mut Vec::new(),
+                    ),
+                    None => vec![Source::In(i)],
+                },
+            );
+        }
+        for name in netlist.chip.outs.iter().chain(&netlist.rails) {
+            probe(name, resolve(&self.scopes, scope, name, &mut Vec::new()));
+        }
+        for inst in &netlist.instances {
+            path.push(inst.name.clone());
+            match self.scopes[scope].children[&inst.name] {
+                Child::Primitive(p) => self.primitive_probes(p, path, probes),
+                Child::Scope(s) => self.scope_probes(s, path, probes),
+            }
+            path.pop();
+        }
+    }
+
+    fn primitive_probes(&self, p: usize, path: &[String], probes: &mut Vec<Probe>) {
+        let primitive = &self.primitives[p];
+        for (name, sources) in primitive.chip.ins.iter().zip(&primitive.ins) {
+            probes.push(Probe {
+                scope: path.to_vec(),
+                name: name.clone(),
+                sources: sources.clone(),
+            });
+        }
+        for (o, name) in primitive.chip.outs.iter().enumerate() {
+            probes.push(Probe {
+                scope: path.to_vec(),
+                name: name.clone(),
+                sources: vec![Source::Primitive(p, o)],
+            });
+        }
+    }
+}
+
+/// Adds the scope for `netlist` and everything inside it, returning its index
+fn expand<'a>(
+    netlist: Netlist<'a>,
+    parent: Option<(usize, String)>,
+    program: &'a Program,
+    scopes: &mut Vec<Scope<'a>>,
+    primitives: &mut Vec<Primitive<'a>>,
+    owners: &mut Vec<(usize, String)>,
+) -> usize {
+    let index = scopes.len();
+    let instances = netlist.instances.clone();
+    scopes.push(Scope {
+        netlist,
+        parent,
+        children: HashMap::new(),
+    });
+    for inst in instances {
+        let netlist = Netlist::new(inst.chip, program);
+        let child = match netlist.custom.clone() {
+            Some(custom) => {
+                primitives.push(Primitive {
+                    chip: inst.chip,
+                    custom,
+                    path: scope_path(scopes, index, &inst.name),
+                    ins: Vec::new(),
+                });
+                owners.push((index, inst.name.clone()));
+                Child::Primitive(primitives.len() - 1)
+            }
+            None => Child::Scope(expand(
+                netlist,
+                Some((index, inst.name.clone())),
+                program,
+                scopes,
+                primitives,
+                owners,
+            )),
+        };
+        scopes[index].children.insert(inst.name, child);
+    }
+    index
+}
+
+/// `name` inside `scope`, prefixed by the instances containing the scope
+fn scope_path(scopes: &[Scope], scope: usize, name: &str) -> String {
+    match &scopes[scope].parent {
+        Some((parent, inst)) => scope_path(scopes, *parent, &format!("{}.{}", inst, name)),
+        None => name.to_string(),
+    }
+}
+
+/// Everything that ends up driving `reader` in `scope`, going through RAILs and into and out
+/// of sub-chips. Loops made only of wires can't add anything, so they're cut
+fn resolve(
+    scopes: &[Scope],
+    scope: usize,
+    reader: &str,
+    visiting: &mut Vec<(usize, String)>,
+) -> Vec<Source> {
+    let key = (scope, reader.to_string());
+    if visiting.contains(&key) {
+        return Vec::new();
+    }
+    visiting.push(key);
+    let netlist = &scopes[scope].netlist;
+    let mut sources = Vec::new();
+    for driver in netlist.drivers_of(reader) {
+        let found = match driver.split_once('.') {
+            Some((inst, pin)) => match scopes[scope].children[inst] {
+                Child::Primitive(p) => {
+                    let chip = netlist.instance(inst).unwrap().chip;
+                    vec![Source::Primitive(
+                        p,
+                        chip.outs.iter().position(|o| o == pin).unwrap(),
+                    )]
+                }
+                Child::Scope(s) => resolve(scopes, s, pin, visiting),
+            },
+            None if netlist.rails.iter().any(|r| r == driver) => {
+                resolve(scopes, scope, driver, visiting)
+            }
+            None => match &scopes[scope].parent {
+                Some((parent, inst)) => {
+                    resolve(scopes, *parent, &format!("{}.{}", inst, driver), visiting)
+                }
+                None => vec![Source::In(
+                    netlist.chip.ins.iter().position(|i| i == driver).unwrap(),
+                )],
+            },
+        };
+        for source in found {
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    }
+    visiting.pop();
+    sources
+}
+
+/// Every chip used by `chip`, directly or not, each one after the chips it uses
+pub fn dependencies<'a>(chip: &Chip, program: &'a Program) -> Vec<&'a Chip> {
+    fn visit<'a>(chip: &Chip, program: &'a Program, found: &mut Vec<&'a Chip>) {
+        for statement in &chip.ast {
+            if let AST::USE(p, _) = statement {
+                let used = program.get_chip(p);
+                if !found.iter().any(|c| c.name == used.name) {
+                    visit(used, program, found);
+                    found.push(used);
+                }
+            }
+        }
+    }
+    let mut found = Vec::new();
+    visit(chip, program, &mut found);
+    found
+}
+
+/// Name usable as an identifier in most target languages
+pub fn chip_ident(name: &str) -> String {
+    name.replace('.', "_")
+}
+
+/// Suffixes names that are one of `keywords` and prefixes the ones starting with a digit,
+/// for the languages without escaped identifiers
+pub fn ident(name: &str, keywords: &[&str]) -> String {
+    if keywords.contains(&name) {
+        format!("{}_", name)
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Error for a CUSTOM chip `backend` doesn't implement
+pub fn custom_error(chip: &Chip, custom: &str, backend: &'static str) -> CompileError {
+    CompileError::new(
+        ErrorKind::InvalidCustom(custom.into(), backend),
+        None,
+        chip.file_name(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn connections_go_from_driver_to_reader_in_any_order() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN i\nOUT o\nRAIL r\nCHIP not n\n\
+             CONNECT i n.i\nCONNECT n.o r\nCONNECT o r\n",
+        );
+        let netlist = Netlist::new(&chip, &program);
+        let pairs = |v: &[(&str, &str)]| {
+            v.iter()
+                .map(|(r, d)| (r.to_string(), d.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            netlist.connections,
+            pairs(&[("n.i", "i"), ("r", "n.o"), ("o", "r")])
+        );
+        assert!(netlist.is_reader("n.i") && netlist.is_driver("n.o"));
+        assert!(!netlist.is_reader("r") && !netlist.is_driver("r"));
+        assert_eq!(netlist.or_of("o", |d| d.into(), " | ", "0"), "r");
+        assert_eq!(netlist.or_of("n.i", |d| d.into(), " | ", "0"), "i");
+    }
+
+    #[test]
+    fn eval_order_puts_drivers_first() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN i\nOUT o\nRAIL r\nCHIP not b\nCHIP not a\n\
+             CONNECT i a.i\nCONNECT a.o r\nCONNECT r b.i\nCONNECT b.o o\n",
+        );
+        let netlist = Netlist::new(&chip, &program);
+        assert_eq!(
+            netlist.eval_order(),
+            Some(vec![
+                Step::Instance(1),
+                Step::Rail("r".into()),
+                Step::Instance(0)
+            ])
+        );
+    }
+
+    #[test]
+    fn idents_have_to_be_different() {
+        let (mut chip, program) = parse_chip("test", "USE STD.NOT AS not\nCHIP not n\n");
+        let flat = |n: &str| n.replace('.', "_");
+        assert!(Netlist::new(&chip, &program)
+            .check_idents("test", flat)
+            .is_ok());
+        chip.ast.push(AST::RAIL("n_o".into()));
+        let error = Netlist::new(&chip, &program)
+            .check_idents("test", flat)
+            .unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::NameClash("n_o".into(), "n.o".into(), "test")
+        );
+    }
+
+    #[test]
+    fn loops_start_at_a_sub_chip_output() {
+        let (chip, program) = parse_chip("test", include_str!("../test.chip"));
+        let netlist = Netlist::new(&chip, &program);
+        assert_eq!(netlist.eval_order(), None);
+        assert_eq!(
+            netlist.find_loop(),
+            Some(vec![
+                "not1.o".into(),
+                "not2.i".into(),
+                "not2.o".into(),
+                "not1.i".into()
+            ])
+        );
+        let error = netlist.loop_error(|path| ErrorKind::NotCombinational("test".into(), path));
+        assert_eq!(error.labels.len(), 1);
+        assert_eq!(
+            error.kind.notes(),
+            ["the loop is not1.o -> not2.i -> not2.o -> not1.i"]
+        );
+    }
+}
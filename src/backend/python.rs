@@ -0,0 +1,175 @@
+pub struct PythonBackend;
+use super::netlist::{self, Netlist, Step};
+use super::Backend;
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+impl Backend for PythonBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut file = String::new();
+        for dep in netlist::dependencies(&chip, &program) {
+            writeln!(file, "{}\n", gen_class(dep, &program)?).unwrap();
+        }
+        write!(file, "{}", gen_class(&chip, &program)?).unwrap();
+        Ok(file)
+    }
+}
+
+fn gen_class(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    let mut class = String::new();
+    writeln!(class, "class {}:", ident(&netlist::chip_ident(&chip.name))).unwrap();
+    writeln!(class, "    def __init__(self):").unwrap();
+    if netlist.instances.is_empty() {
+        writeln!(class, "        pass").unwrap();
+    }
+    for inst in &netlist.instances {
+        writeln!(
+            class,
+            "        self.{} = {}()",
+            ident(&inst.name),
+            ident(&netlist::chip_ident(&inst.chip.name))
+        )
+        .unwrap();
+    }
+    writeln!(class).unwrap();
+    write!(class, "{}", gen_run_code(chip, &netlist)?).unwrap();
+    Ok(class)
+}
+
+fn gen_run_code(chip: &Chip, netlist: &Netlist) -> Result<String, CompileError> {
+    let mut func = String::new();
+    let params = chip
+        .ins
+        .iter()
+        .map(|i| format!(", {}", ident(i)))
+        .collect::<String>();
+    writeln!(func, "    def run(self{}):", params).unwrap();
+
+    if let Some(custom) = &netlist.custom {
+        let code =
+            get_custom_code(custom).ok_or_else(|| netlist::custom_error(chip, custom, "Python"))?;
+        writeln!(func, "        {}", code).unwrap();
+        return Ok(func);
+    }
+
+    let order = netlist.eval_order().ok_or_else(|| {
+        netlist.loop_error(|path| ErrorKind::Loop(chip.name.clone(), "Python", path))
+    })?;
+    for step in order {
+        match step {
+            Step::Rail(r) => {
+                writeln!(func, "        {} = {}", ident(&r), value(netlist, &r)).unwrap();
+            }
+            Step::Instance(i) => {
+                let inst = &netlist.instances[i];
+                let args = inst
+                    .chip
+                    .ins
+                    .iter()
+                    .map(|p| value(netlist, &inst.pin(p)))
+                    .collect::<Vec<_>>();
+                writeln!(
+                    func,
+                    "        {0} = self.{0}.run({1})",
+                    ident(&inst.name),
+                    args.join(", ")
+                )
+                .unwrap();
+            }
+        }
+    }
+    let outs = chip
+        .outs
+        .iter()
+        .map(|o| value(netlist, o))
+        .collect::<Vec<_>>();
+    writeln!(func, "        return [{}]", outs.join(", ")).unwrap();
+    Ok(func)
+}
+
+/// Sub-chip outputs are read from the lists returned by `run`
+fn value(netlist: &Netlist, reader: &str) -> String {
+    let driver = |d: &str| match d.split_once('.') {
+        Some((inst, pin)) => {
+            let chip = netlist.instance(inst).unwrap().chip;
+            let index = chip.outs.iter().position(|o| o == pin).unwrap();
+            format!("{}[{}]", ident(inst), index)
+        }
+        None => ident(d),
+    };
+    // `or` returns one of its operands and INs are passed through, so every value is
+    // normalized in case the inputs are 0/1 or numpy bools
+    if netlist.drivers_of(reader).is_empty() {
+        "False".into()
+    } else {
+        format!("bool({})", netlist.or_of(reader, driver, " or ", "False"))
+    }
+}
+
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class",
+    "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if",
+    "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try",
+    "while", "with", "yield", "self", "bool",
+];
+
+/// `self` and `bool` are in the keywords so they're not shadowed
+fn ident(name: &str) -> String {
+    netlist::ident(name, KEYWORDS)
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("return [not i]"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_a_class_normalizing_values_to_bool() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let python = PythonBackend::compile(chip, program).unwrap();
+        assert!(python.starts_with(
+            "class STD_NOT:\n    def __init__(self):\n        pass\n\n\
+             \x20   def run(self, i):\n        return [not i]\n"
+        ));
+        let xor = &python[python.find("class xor:").unwrap()..];
+        assert_eq!(
+            xor,
+            "class xor:\n    def __init__(self):\n\
+             \x20       self.nt1 = STD_NOT()\n        self.nt2 = STD_NOT()\n\
+             \x20       self.and1 = STD_AND()\n        self.and2 = STD_AND()\n\n\
+             \x20   def run(self, in0, in1):\n\
+             \x20       nt1 = self.nt1.run(bool(in0))\n\
+             \x20       nt2 = self.nt2.run(bool(in1))\n\
+             \x20       and1 = self.and1.run(bool(in0), bool(nt2[0]))\n\
+             \x20       and2 = self.and2.run(bool(nt1[0]), bool(in1))\n\
+             \x20       return [bool(and1[0] or and2[0])]\n"
+        );
+    }
+
+    #[test]
+    fn keywords_are_suffixed_and_undriven_outs_are_false() {
+        let (chip, program) = parse_chip(
+            "test",
+            "IN self\nIN 2\nOUT bool\nOUT o\nCONNECT self bool\n",
+        );
+        let python = PythonBackend::compile(chip, program).unwrap();
+        assert_eq!(
+            python,
+            "class test:\n    def __init__(self):\n        pass\n\n\
+             \x20   def run(self, self_, _2):\n        return [bool(self_), False]\n"
+        );
+    }
+}
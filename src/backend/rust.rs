@@ -0,0 +1,193 @@
+pub struct RustBackend;
+use super::netlist::{self, Netlist, Step};
+use super::Backend;
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+impl Backend for RustBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut file = String::new();
+        // Names are kept as they are in the .chip files, like the JS classes
+        writeln!(
+            file,
+            "#![allow(non_camel_case_types, non_snake_case, unused_variables, dead_code, clippy::all)]\n"
+        )
+        .unwrap();
+        for dep in netlist::dependencies(&chip, &program) {
+            writeln!(file, "{}", gen_struct(dep, &program)?).unwrap();
+        }
+        write!(file, "{}", gen_struct(&chip, &program)?).unwrap();
+        Ok(file)
+    }
+}
+
+fn gen_struct(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    let name = ident(&netlist::chip_ident(&chip.name));
+    let mut code = String::new();
+    writeln!(code, "#[derive(Debug, Clone, Default)]").unwrap();
+    writeln!(code, "pub struct {} {{", name).unwrap();
+    for inst in &netlist.instances {
+        writeln!(
+            code,
+            "{}: {},",
+            ident(&inst.name),
+            ident(&netlist::chip_ident(&inst.chip.name))
+        )
+        .unwrap();
+    }
+    writeln!(code, "}}\n").unwrap();
+    writeln!(code, "impl {} {{", name).unwrap();
+    writeln!(code, "pub fn new() -> Self {{\nSelf::default()\n}}\n").unwrap();
+    writeln!(code, "{}", gen_run_code(chip, &netlist)?).unwrap();
+    code += "}\n";
+    Ok(code)
+}
+
+fn gen_run_code(chip: &Chip, netlist: &Netlist) -> Result<String, CompileError> {
+    let mut func = String::new();
+    let params = chip
+        .ins
+        .iter()
+        .map(|i| format!(", {}: bool", ident(i)))
+        .collect::<String>();
+    writeln!(
+        func,
+        "pub fn run(&mut self{}) -> [bool; {}] {{",
+        params,
+        chip.outs.len()
+    )
+    .unwrap();
+
+    if let Some(custom) = &netlist.custom {
+        let code =
+            get_custom_code(custom).ok_or_else(|| netlist::custom_error(chip, custom, "Rust"))?;
+        writeln!(func, "{}", code).unwrap();
+        func += "}";
+        return Ok(func);
+    }
+
+    let order = netlist.eval_order().ok_or_else(|| {
+        netlist.loop_error(|path| ErrorKind::Loop(chip.name.clone(), "Rust", path))
+    })?;
+    for step in order {
+        match step {
+            Step::Rail(r) => {
+                writeln!(func, "let {} = {};", ident(&r), value(netlist, &r)).unwrap();
+            }
+            Step::Instance(i) => {
+                let inst = &netlist.instances[i];
+                let args = inst
+                    .chip
+                    .ins
+                    .iter()
+                    .map(|p| value(netlist, &inst.pin(p)))
+                    .collect::<Vec<_>>();
+                writeln!(
+                    func,
+                    "let {0} = self.{0}.run({1});",
+                    ident(&inst.name),
+                    args.join(", ")
+                )
+                .unwrap();
+            }
+        }
+    }
+    let outs = chip
+        .outs
+        .iter()
+        .map(|o| value(netlist, o))
+        .collect::<Vec<_>>();
+    writeln!(func, "[{}]", outs.join(", ")).unwrap();
+    func += "}";
+    Ok(func)
+}
+
+/// Sub-chip outputs are read from the arrays returned by `run`
+fn value(netlist: &Netlist, reader: &str) -> String {
+    let driver = |d: &str| match d.split_once('.') {
+        Some((inst, pin)) => {
+            let chip = netlist.instance(inst).unwrap().chip;
+            let index = chip.outs.iter().position(|o| o == pin).unwrap();
+            format!("{}[{}]", ident(inst), index)
+        }
+        None => ident(d),
+    };
+    netlist.or_of(reader, driver, " || ", "false")
+}
+
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "do", "dyn", "else",
+    "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static", "struct",
+    "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where",
+    "while", "yield",
+];
+
+/// Uses raw identifiers for keywords and prefixes names starting with a digit,
+/// the keywords that can't be raw identifiers get a `_` suffix instead
+fn ident(name: &str) -> String {
+    if ["self", "Self", "super", "crate"].contains(&name) {
+        format!("{}_", name)
+    } else if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("[!i]"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_a_struct_owning_its_sub_chips() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let rust = RustBackend::compile(chip, program).unwrap();
+        assert!(rust.contains(
+            "impl STD_NOT {\npub fn new() -> Self {\nSelf::default()\n}\n\n\
+             pub fn run(&mut self, i: bool) -> [bool; 1] {\n[!i]\n}\n}\n"
+        ));
+        let xor = &rust[rust
+            .find("#[derive(Debug, Clone, Default)]\npub struct xor")
+            .unwrap()..];
+        assert_eq!(
+            xor,
+            "#[derive(Debug, Clone, Default)]\npub struct xor {\n\
+             nt1: STD_NOT,\nnt2: STD_NOT,\nand1: STD_AND,\nand2: STD_AND,\n}\n\n\
+             impl xor {\npub fn new() -> Self {\nSelf::default()\n}\n\n\
+             pub fn run(&mut self, in0: bool, in1: bool) -> [bool; 1] {\n\
+             let nt1 = self.nt1.run(in0);\nlet nt2 = self.nt2.run(in1);\n\
+             let and1 = self.and1.run(in0, nt2[0]);\nlet and2 = self.and2.run(nt1[0], in1);\n\
+             [and1[0] || and2[0]]\n}\n}\n"
+        );
+    }
+
+    #[test]
+    fn keywords_are_raw_identifiers() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN match\nOUT self\nCHIP not fn\nCONNECT match fn.i\nCONNECT fn.o self\n",
+        );
+        let rust = RustBackend::compile(chip, program).unwrap();
+        assert!(rust.contains(
+            "pub fn run(&mut self, r#match: bool) -> [bool; 1] {\n\
+             let r#fn = self.r#fn.run(r#match);\n[r#fn[0]]\n}\n"
+        ));
+    }
+}
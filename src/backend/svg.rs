@@ -0,0 +1,675 @@
+pub struct SvgBackend;
+use super::netlist::Netlist;
+use super::Backend;
+use crate::error::CompileError;
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+const MARGIN: i32 = 20;
+/// Space between the nodes of a column, and on each side of the wires between two columns
+const SPACING: i32 = 20;
+/// Distance between wires running next to each other
+const TRACK: i32 = 10;
+/// Vertical space taken by each pin of a box
+const PIN: i32 = 20;
+/// Height of the names at the top of a box
+const HEADER: i32 = 30;
+const CHAR_WIDTH: i32 = 6;
+
+impl Backend for SvgBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        Ok(Schematic::new(&chip, &program).render())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Gate {
+    Not,
+    And,
+    Nand,
+    Xor,
+}
+
+impl Gate {
+    /// Only the STD chips get a gate symbol, and only if they have the pins the symbol expects
+    fn of(chip: &Chip) -> Option<Self> {
+        let gate = match chip.name.as_str() {
+            "STD.NOT" => Gate::Not,
+            "STD.AND" => Gate::And,
+            "STD.NAND" => Gate::Nand,
+            "STD.XOR" => Gate::Xor,
+            _ => return None,
+        };
+        let ins = if gate == Gate::Not { 1 } else { 2 };
+        if chip.ins.len() == ins && chip.outs.len() == 1 {
+            Some(gate)
+        } else {
+            None
+        }
+    }
+
+    /// The symbol, drawn in a 40x40 box
+    fn path(self) -> &'static str {
+        match self {
+            Gate::Not => {
+                "<path d=\"M0 5 L30 20 L0 35 Z M38 20 H40\"/><circle cx=\"34\" cy=\"20\" r=\"4\"/>"
+            }
+            Gate::And => "<path d=\"M0 0 H20 A20 20 0 0 1 20 40 H0 Z\"/>",
+            Gate::Nand => {
+                "<path d=\"M0 0 H14 A18 20 0 0 1 14 40 H0 Z\"/><circle cx=\"36\" cy=\"20\" r=\"4\"/>"
+            }
+            Gate::Xor => "<path d=\"M6 0 Q28 0 40 20 Q28 40 6 40 Q16 20 6 0 Z\"/><path d=\"M0 0 Q10 20 0 40\" fill=\"none\"/>",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Shape {
+    In,
+    Out,
+    Rail,
+    Gate(Gate),
+    /// Any other chip, with the name of the chip
+    Box(String),
+    /// The code of a CUSTOM chip, when the chip drawn is a primitive
+    Custom,
+    /// Where a wire crosses a column it skips, see `Schematic::add_waypoints`
+    Waypoint,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// The name used in the CONNECTs, the instance name for sub-chips
+    name: String,
+    shape: Shape,
+    ins: Vec<String>,
+    outs: Vec<String>,
+    column: usize,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+}
+
+impl Node {
+    fn new(name: &str, shape: Shape, ins: Vec<String>, outs: Vec<String>) -> Self {
+        let (w, h) = match &shape {
+            Shape::In | Shape::Out => (text_width(name) + 20, PIN),
+            Shape::Rail => (10, 10),
+            Shape::Waypoint => (0, 0),
+            Shape::Gate(_) => (40, 40),
+            Shape::Box(_) | Shape::Custom => {
+                let widest = |pins: &[String]| pins.iter().map(|p| text_width(p)).max();
+                let pins = widest(&ins).unwrap_or(0) + widest(&outs).unwrap_or(0) + 20;
+                let title = match &shape {
+                    Shape::Box(chip) => text_width(name).max(text_width(chip)),
+                    _ => text_width(name),
+                };
+                let rows = ins.len().max(outs.len()).max(1) as i32;
+                (title.max(pins) + 20, HEADER + rows * PIN)
+            }
+        };
+        Self {
+            name: name.to_string(),
+            shape,
+            ins,
+            outs,
+            column: 0,
+            x: 0,
+            y: 0,
+            w,
+            h,
+        }
+    }
+
+    /// Where a wire attaches to the node, `pin` is `None` for INs, OUTs and RAILs
+    fn pin_pos(&self, pin: Option<&str>) -> (i32, i32) {
+        let pin_y = |index: usize, count: usize| {
+            let top = match self.shape {
+                Shape::Box(_) | Shape::Custom => HEADER,
+                _ => 0,
+            };
+            let (index, count) = (index as i32, count as i32);
+            self.y + top + (self.h - top) * (2 * index + 1) / (2 * count)
+        };
+        match (&self.shape, pin) {
+            (Shape::In, _) => (self.x + self.w, self.y + self.h / 2),
+            (Shape::Out, _) => (self.x, self.y + self.h / 2),
+            (Shape::Rail | Shape::Waypoint, _) => (self.x + self.w / 2, self.y + self.h / 2),
+            (_, Some(pin)) => match self.ins.iter().position(|p| p == pin) {
+                Some(i) => (self.x, pin_y(i, self.ins.len())),
+                None => {
+                    let i = self.outs.iter().position(|p| p == pin).unwrap();
+                    (self.x + self.w, pin_y(i, self.outs.len()))
+                }
+            },
+            (_, None) => unreachable!("Pin of {} without a name", self.name),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut node = String::new();
+        writeln!(node, "<g transform=\"translate({} {})\">", self.x, self.y).unwrap();
+        let (w, h) = (self.w, self.h);
+        match &self.shape {
+            Shape::Waypoint => {}
+            Shape::In | Shape::Out => {
+                writeln!(
+                    node,
+                    "<path d=\"M0 0 H{0} L{1} {2} L{0} {3} H0 Z\" fill=\"white\" stroke=\"black\"/>",
+                    w - 10,
+                    w,
+                    h / 2,
+                    h
+                )
+                .unwrap();
+                writeln!(node, "{}", text(5, h / 2 + 3, "start", &self.name)).unwrap();
+            }
+            Shape::Rail => {
+                writeln!(
+                    node,
+                    "<circle cx=\"{0}\" cy=\"{1}\" r=\"3\"/>",
+                    w / 2,
+                    h / 2
+                )
+                .unwrap();
+                writeln!(node, "{}", text(w / 2, -2, "middle", &self.name)).unwrap();
+            }
+            Shape::Gate(gate) => {
+                writeln!(
+                    node,
+                    "<g fill=\"white\" stroke=\"black\">{}</g>",
+                    gate.path()
+                )
+                .unwrap();
+                writeln!(node, "{}", text(w / 2, -3, "middle", &self.name)).unwrap();
+            }
+            Shape::Box(_) | Shape::Custom => {
+                let dash = match self.shape {
+                    Shape::Custom => " stroke-dasharray=\"4 2\"",
+                    _ => "",
+                };
+                writeln!(
+                    node,
+                    "<rect width=\"{}\" height=\"{}\" fill=\"white\" stroke=\"black\"{}/>",
+                    w, h, dash
+                )
+                .unwrap();
+                writeln!(node, "{}", text(w / 2, 12, "middle", &self.name)).unwrap();
+                if let Shape::Box(chip) = &self.shape {
+                    writeln!(node, "{}", text(w / 2, 24, "middle", chip)).unwrap();
+                }
+                for pin in &self.ins {
+                    let (_, y) = self.pin_pos(Some(pin));
+                    writeln!(node, "{}", text(3, y - self.y + 3, "start", pin)).unwrap();
+                }
+                for pin in &self.outs {
+                    let (_, y) = self.pin_pos(Some(pin));
+                    writeln!(node, "{}", text(w - 3, y - self.y + 3, "end", pin)).unwrap();
+                }
+            }
+        }
+        node += "</g>";
+        node
+    }
+}
+
+/// A pin: the node and, for sub-chips, the name of the pin
+type Endpoint = (usize, Option<String>);
+
+/// A driver and everything it drives
+#[derive(Debug, Clone)]
+struct Net {
+    driver: Endpoint,
+    readers: Vec<Endpoint>,
+}
+
+/// The chip laid out in columns, INs on the left and OUTs on the right,
+/// with every node placed after the ones driving it
+#[derive(Debug, Clone)]
+struct Schematic {
+    name: String,
+    nodes: Vec<Node>,
+    nets: Vec<Net>,
+    columns: Vec<Vec<usize>>,
+    /// Left side of the wires running between a column and the next one
+    gaps: Vec<i32>,
+    width: i32,
+    /// Bottom of the nodes, wires going back to an earlier column run below it
+    bottom: i32,
+}
+
+impl Schematic {
+    fn new(chip: &Chip, program: &Program) -> Self {
+        let netlist = Netlist::new(chip, program);
+        let mut nodes = Vec::new();
+        let mut connections = netlist.connections.clone();
+        for i in &chip.ins {
+            nodes.push(Node::new(i, Shape::In, Vec::new(), Vec::new()));
+        }
+        for rail in &netlist.rails {
+            nodes.push(Node::new(rail, Shape::Rail, Vec::new(), Vec::new()));
+        }
+        for inst in &netlist.instances {
+            let shape = match Gate::of(inst.chip) {
+                Some(gate) => Shape::Gate(gate),
+                None => Shape::Box(inst.chip.name.clone()),
+            };
+            nodes.push(Node::new(
+                &inst.name,
+                shape,
+                inst.chip.ins.clone(),
+                inst.chip.outs.clone(),
+            ));
+        }
+        if let Some(custom) = &netlist.custom {
+            // Names in chips can't have spaces, so this can't clash with them
+            let name = format!("CUSTOM {}", custom);
+            nodes.push(Node::new(
+                &name,
+                Shape::Custom,
+                chip.ins.clone(),
+                chip.outs.clone(),
+            ));
+            connections.extend(
+                chip.ins
+                    .iter()
+                    .map(|i| (format!("{}.{}", name, i), i.clone())),
+            );
+            connections.extend(
+                chip.outs
+                    .iter()
+                    .map(|o| (o.clone(), format!("{}.{}", name, o))),
+            );
+        }
+        for o in &chip.outs {
+            nodes.push(Node::new(o, Shape::Out, Vec::new(), Vec::new()));
+        }
+
+        let endpoint = |name: &str| -> Endpoint {
+            let (node, pin) = match name.split_once('.') {
+                Some((node, pin)) => (node, Some(pin.to_string())),
+                None => (name, None),
+            };
+            (nodes.iter().position(|n| n.name == node).unwrap(), pin)
+        };
+        let wires = connections
+            .iter()
+            .map(|(reader, driver)| (endpoint(driver), endpoint(reader)))
+            .collect::<Vec<_>>();
+
+        let mut schematic = Self {
+            name: chip.name.clone(),
+            nodes,
+            nets: group(wires),
+            columns: Vec::new(),
+            gaps: Vec::new(),
+            width: 0,
+            bottom: 0,
+        };
+        schematic.assign_columns();
+        schematic.add_waypoints();
+        schematic.place_rows();
+        schematic.place_columns();
+        schematic
+    }
+
+    /// Nodes driving `node`
+    fn drivers(&self, node: usize) -> Vec<usize> {
+        self.nets
+            .iter()
+            .filter(|n| n.readers.iter().any(|r| r.0 == node))
+            .map(|n| n.driver.0)
+            .collect()
+    }
+
+    /// Each node goes one column after the furthest node driving it, ignoring the
+    /// connections that close a feedback loop
+    fn assign_columns(&mut self) {
+        fn depth(
+            schematic: &Schematic,
+            node: usize,
+            memo: &mut Vec<Option<usize>>,
+            visiting: &mut Vec<bool>,
+        ) -> usize {
+            if let Some(d) = memo[node] {
+                return d;
+            }
+            if let Shape::In = schematic.nodes[node].shape {
+                return 0;
+            }
+            visiting[node] = true;
+            let drivers = schematic
+                .drivers(node)
+                .into_iter()
+                .filter(|d| !visiting[*d])
+                .collect::<Vec<_>>();
+            let d = drivers
+                .into_iter()
+                .map(|d| depth(schematic, d, memo, visiting) + 1)
+                .max()
+                .unwrap_or(1);
+            visiting[node] = false;
+            memo[node] = Some(d);
+            d
+        }
+        let mut memo = vec![None; self.nodes.len()];
+        let mut visiting = vec![false; self.nodes.len()];
+        let columns = (0..self.nodes.len())
+            .map(|n| depth(self, n, &mut memo, &mut visiting))
+            .collect::<Vec<_>>();
+        // OUTs all go in the last column
+        let last = (0..self.nodes.len())
+            .filter(|n| !matches!(self.nodes[*n].shape, Shape::Out))
+            .map(|n| columns[n])
+            .max()
+            .unwrap_or(0)
+            + 1;
+        for (n, node) in self.nodes.iter_mut().enumerate() {
+            node.column = match node.shape {
+                Shape::Out => last,
+                _ => columns[n],
+            };
+        }
+        self.columns = vec![Vec::new(); last + 1];
+        for (n, node) in self.nodes.iter().enumerate() {
+            self.columns[node.column].push(n);
+        }
+    }
+
+    /// Wires skipping columns go through a waypoint in each column they cross, which takes a row
+    /// of that column so the wire doesn't run over its nodes. Readers of the same driver share them
+    fn add_waypoints(&mut self) {
+        let mut wires = Vec::new();
+        let mut waypoints: Vec<(Endpoint, usize, usize)> = Vec::new();
+        for Net { driver, readers } in std::mem::take(&mut self.nets) {
+            let column = self.nodes[driver.0].column;
+            for reader in readers {
+                let mut from = driver.clone();
+                for c in column + 1..self.nodes[reader.0].column {
+                    let existing = waypoints
+                        .iter()
+                        .find(|w| w.0 == driver && w.1 == c)
+                        .map(|w| w.2);
+                    let waypoint = match existing {
+                        Some(w) => w,
+                        None => {
+                            let mut node = Node::new("", Shape::Waypoint, Vec::new(), Vec::new());
+                            node.column = c;
+                            self.nodes.push(node);
+                            let w = self.nodes.len() - 1;
+                            self.columns[c].push(w);
+                            waypoints.push((driver.clone(), c, w));
+                            wires.push((from, (w, None)));
+                            w
+                        }
+                    };
+                    from = (waypoint, None);
+                }
+                wires.push((from, reader));
+            }
+        }
+        self.nets = group(wires);
+    }
+
+    /// Stacks the nodes of each column, sorted by the height of the nodes driving them
+    /// so wires cross as little as possible, and centers the columns
+    fn place_rows(&mut self) {
+        let height = |nodes: &[Node], column: &[usize]| {
+            column.iter().map(|n| nodes[*n].h + SPACING).sum::<i32>() - SPACING
+        };
+        let tallest = self
+            .columns
+            .iter()
+            .map(|c| height(&self.nodes, c))
+            .max()
+            .unwrap_or(0);
+        for c in 0..self.columns.len() {
+            let mut column = self.columns[c].clone();
+            let center = |n: usize| self.nodes[n].y + self.nodes[n].h / 2;
+            column.sort_by_key(|n| {
+                let drivers = self
+                    .drivers(*n)
+                    .into_iter()
+                    .filter(|d| self.nodes[*d].column < c)
+                    .collect::<Vec<_>>();
+                if drivers.is_empty() {
+                    i32::MAX
+                } else {
+                    drivers.iter().map(|d| center(*d)).sum::<i32>() / drivers.len() as i32
+                }
+            });
+            let mut y = MARGIN + (tallest - height(&self.nodes, &column)) / 2;
+            for n in &column {
+                self.nodes[*n].y = y;
+                y += self.nodes[*n].h + SPACING;
+            }
+            self.columns[c] = column;
+        }
+        self.bottom = MARGIN + tallest;
+    }
+
+    /// Wires that go right leave the driver on a track in the gap after its column, wires
+    /// going back also need a track in the gap before the column they go to
+    fn tracks(&self) -> Vec<i32> {
+        let mut tracks = vec![0; self.columns.len()];
+        for net in &self.nets {
+            let column = self.nodes[net.driver.0].column;
+            tracks[column] += 1;
+            for reader in &net.readers {
+                let target = self.nodes[reader.0].column;
+                if target <= column {
+                    tracks[target - 1] += 1;
+                }
+            }
+        }
+        tracks
+    }
+
+    fn place_columns(&mut self) {
+        let tracks = self.tracks();
+        let mut x = MARGIN;
+        for (c, column) in self.columns.iter().enumerate() {
+            let width = column.iter().map(|n| self.nodes[*n].w).max().unwrap_or(0);
+            for n in column {
+                let node = &mut self.nodes[*n];
+                // INs are aligned to the right and OUTs to the left, so their pins line up
+                node.x = match node.shape {
+                    Shape::In => x + width - node.w,
+                    Shape::Out => x,
+                    _ => x + (width - node.w) / 2,
+                };
+            }
+            x += width;
+            if c + 1 < self.columns.len() {
+                self.gaps.push(x + SPACING);
+                x += 2 * SPACING + TRACK * (tracks[c] - 1).max(0);
+            }
+        }
+        self.width = x + MARGIN;
+    }
+
+    fn render(&self) -> String {
+        let mut wires = String::new();
+        let mut dots = Vec::new();
+        let mut used = vec![0; self.gaps.len()];
+        let mut track = |gap: usize| {
+            used[gap] += 1;
+            self.gaps[gap] + TRACK * (used[gap] - 1)
+        };
+        let mut lanes = 0;
+        for net in &self.nets {
+            let driver = &self.nodes[net.driver.0];
+            let (x, y) = driver.pin_pos(net.driver.1.as_deref());
+            let tx = track(driver.column);
+            // Wires meeting on the track, as `(y, wires to the left, wires to the right)`
+            let mut joins = vec![(y, 1, 0)];
+            write!(wires, "M{} {} H{}", x, y, tx).unwrap();
+            for reader in &net.readers {
+                let node = &self.nodes[reader.0];
+                let (rx, ry) = node.pin_pos(reader.1.as_deref());
+                if node.column > driver.column {
+                    joins.push((ry, 0, 1));
+                    write!(wires, " M{} {} H{}", tx, ry, rx).unwrap();
+                } else {
+                    // Feedback goes around, below every node
+                    let lane = self.bottom + SPACING + TRACK * lanes;
+                    lanes += 1;
+                    joins.push((lane, 1, 0));
+                    let bx = track(node.column - 1);
+                    write!(wires, " M{} {} H{} V{} H{}", tx, lane, bx, ry, rx).unwrap();
+                }
+            }
+            let top = joins.iter().map(|j| j.0).min().unwrap();
+            let bottom = joins.iter().map(|j| j.0).max().unwrap();
+            writeln!(wires, " M{} {} V{}", tx, top, bottom).unwrap();
+            // A dot wherever three or more wires meet
+            let mut ys = joins.iter().map(|j| j.0).collect::<Vec<_>>();
+            ys.sort_unstable();
+            ys.dedup();
+            for join in ys {
+                let sides = joins
+                    .iter()
+                    .filter(|j| j.0 == join)
+                    .map(|j| j.1 + j.2)
+                    .sum::<i32>();
+                let vertical = if top == bottom {
+                    0
+                } else if join == top || join == bottom {
+                    1
+                } else {
+                    2
+                };
+                if sides + vertical >= 3 {
+                    dots.push((tx, join));
+                }
+            }
+        }
+        let height = self.bottom
+            + MARGIN
+            + if lanes > 0 {
+                SPACING + TRACK * (lanes - 1)
+            } else {
+                0
+            };
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\" font-family=\"monospace\" font-size=\"10\">",
+            self.width, height
+        )
+        .unwrap();
+        writeln!(svg, "<title>{}</title>", escape(&self.name)).unwrap();
+        writeln!(
+            svg,
+            "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>",
+            self.width, height
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            "<path d=\"{}\" fill=\"none\" stroke=\"black\"/>",
+            wires.trim_end().replace('\n', " ")
+        )
+        .unwrap();
+        for node in self
+            .nodes
+            .iter()
+            .filter(|n| !matches!(n.shape, Shape::Waypoint))
+        {
+            writeln!(svg, "{}", node.render()).unwrap();
+        }
+        for (x, y) in dots {
+            writeln!(svg, "<circle cx=\"{}\" cy=\"{}\" r=\"3\"/>", x, y).unwrap();
+        }
+        svg += "</svg>\n";
+        svg
+    }
+}
+
+/// Groups `(driver, reader)` pairs by driver
+fn group(wires: Vec<(Endpoint, Endpoint)>) -> Vec<Net> {
+    let mut nets: Vec<Net> = Vec::new();
+    for (driver, reader) in wires {
+        match nets.iter_mut().find(|n| n.driver == driver) {
+            Some(net) => net.readers.push(reader),
+            None => nets.push(Net {
+                driver,
+                readers: vec![reader],
+            }),
+        }
+    }
+    nets
+}
+
+fn text_width(text: &str) -> i32 {
+    text.chars().count() as i32 * CHAR_WIDTH
+}
+
+fn text(x: i32, y: i32, anchor: &str, contents: &str) -> String {
+    format!(
+        "<text x=\"{}\" y=\"{}\" text-anchor=\"{}\">{}</text>",
+        x,
+        y,
+        anchor,
+        escape(contents)
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn std_chips_are_gates_in_columns_left_to_right() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let svg = SvgBackend::compile(chip, program).unwrap();
+        assert!(svg
+            .starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"366\" height=\"180\""));
+        assert!(svg.contains("<title>xor</title>\n"));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches(Gate::Not.path()).count(), 2);
+        assert_eq!(svg.matches(Gate::And.path()).count(), 2);
+        // The INs are left of the NOTs, which are left of the ANDs, which are left of the OUT
+        let x = |name: &str| {
+            let label = svg.find(&format!(">{}</text>", name)).unwrap();
+            let group = svg[..label].rfind("translate(").unwrap() + "translate(".len();
+            let end = group + svg[group..].find(' ').unwrap();
+            svg[group..end].parse::<i32>().unwrap()
+        };
+        assert!(x("in0") < x("nt1"));
+        assert_eq!(x("nt1"), x("nt2"));
+        assert!(x("nt1") < x("and1"));
+        assert!(x("and1") < x("out"));
+    }
+
+    #[test]
+    fn other_chips_are_boxes_and_custom_code_is_dashed() {
+        let mut program = Program::new();
+        let inv = Chip::parse("lib.inv".into(), "IN i\nOUT o\nCONNECT i o\n", &mut program);
+        program.files.insert("lib.inv".into(), inv.unwrap());
+        let src = "USE lib.inv AS inv\nIN a\nOUT b\nCHIP inv x\nCONNECT a x.i\nCONNECT x.o b\n";
+        let chip = Chip::parse("top".into(), src, &mut program).unwrap();
+        let not = program.files["STD.NOT"].clone();
+        let svg = SvgBackend::compile(chip, program).unwrap();
+        assert!(svg.contains("fill=\"white\" stroke=\"black\"/>\n"));
+        assert!(svg.contains(">x</text>"));
+        assert!(svg.contains(">lib.inv</text>"));
+        assert!(!svg.contains("stroke-dasharray"));
+
+        let svg = SvgBackend::compile(not, Program::new()).unwrap();
+        assert!(svg.contains("stroke=\"black\" stroke-dasharray=\"4 2\"/>\n"));
+        assert!(svg.contains(">CUSTOM NOT</text>"));
+    }
+}
@@ -0,0 +1,152 @@
+pub struct VerilogBackend;
+use super::netlist::{self, Netlist};
+use super::Backend;
+use crate::error::CompileError;
+use crate::lexer::{Chip, Program};
+
+use std::fmt::Write;
+
+impl Backend for VerilogBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut file = String::new();
+        for dep in netlist::dependencies(&chip, &program) {
+            writeln!(file, "{}", gen_module(dep, &program)?).unwrap();
+        }
+        write!(file, "{}", gen_module(&chip, &program)?).unwrap();
+        Ok(file)
+    }
+}
+
+fn gen_module(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    netlist.check_idents("Verilog", signal)?;
+    let mut module = String::new();
+    let ports = chip
+        .ins
+        .iter()
+        .map(|i| format!("input {}", signal(i)))
+        .chain(chip.outs.iter().map(|o| format!("output {}", signal(o))))
+        .collect::<Vec<_>>();
+    writeln!(
+        module,
+        "module {}({});",
+        ident(&netlist::chip_ident(&chip.name)),
+        ports.join(", ")
+    )
+    .unwrap();
+
+    if let Some(custom) = &netlist.custom {
+        let code = get_custom_code(custom)
+            .ok_or_else(|| netlist::custom_error(chip, custom, "Verilog"))?;
+        writeln!(module, "{}", code).unwrap();
+        module += "endmodule\n";
+        return Ok(module);
+    }
+
+    for rail in &netlist.rails {
+        writeln!(module, "wire {};", signal(rail)).unwrap();
+    }
+    for inst in &netlist.instances {
+        for pin in inst.chip.ins.iter().chain(&inst.chip.outs) {
+            writeln!(module, "wire {};", signal(&inst.pin(pin))).unwrap();
+        }
+    }
+    for inst in &netlist.instances {
+        let pins = inst
+            .chip
+            .ins
+            .iter()
+            .chain(&inst.chip.outs)
+            .map(|pin| format!(".{}({})", ident(pin), signal(&inst.pin(pin))))
+            .collect::<Vec<_>>();
+        writeln!(
+            module,
+            "{} {}({});",
+            ident(&netlist::chip_ident(&inst.chip.name)),
+            ident(&inst.name),
+            pins.join(", ")
+        )
+        .unwrap();
+    }
+    for reader in netlist.readers() {
+        let value = netlist.or_of(&reader, signal, " | ", "1'b0");
+        writeln!(module, "assign {} = {};", signal(&reader), value).unwrap();
+    }
+    module += "endmodule\n";
+    Ok(module)
+}
+
+/// Name of the wire for `a` or `chip.pin`
+fn signal(name: &str) -> String {
+    ident(&name.replace('.', "_"))
+}
+
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+    "always", "and", "assign", "begin", "buf", "case", "default", "else", "end", "endcase",
+    "endmodule", "for", "function", "if", "initial", "inout", "input", "integer", "module", "nand",
+    "nor", "not", "or", "output", "reg", "wire", "xnor", "xor",
+];
+
+/// Escapes names that are Verilog keywords or start with a digit
+fn ident(name: &str) -> String {
+    if KEYWORDS.contains(&name) || name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("\\{} ", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("assign o = ~i;"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AST;
+    use crate::error::ErrorKind;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_a_module_of_nots_and_ands() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let verilog = VerilogBackend::compile(chip, program).unwrap();
+        assert!(
+            verilog.starts_with("module STD_NOT(input i, output o);\nassign o = ~i;\nendmodule\n")
+        );
+        let xor = &verilog[verilog.find("module \\xor ").unwrap()..];
+        assert_eq!(
+            xor,
+            "module \\xor (input in0, input in1, output out);\n\
+             wire nt1_i;\nwire nt1_o;\nwire nt2_i;\nwire nt2_o;\n\
+             wire and1_in0;\nwire and1_in1;\nwire and1_out;\n\
+             wire and2_in0;\nwire and2_in1;\nwire and2_out;\n\
+             STD_NOT nt1(.i(nt1_i), .o(nt1_o));\n\
+             STD_NOT nt2(.i(nt2_i), .o(nt2_o));\n\
+             STD_AND and1(.in0(and1_in0), .in1(and1_in1), .out(and1_out));\n\
+             STD_AND and2(.in0(and2_in0), .in1(and2_in1), .out(and2_out));\n\
+             assign out = and1_out | and2_out;\n\
+             assign nt1_i = in0;\nassign nt2_i = in1;\n\
+             assign and1_in0 = in0;\nassign and1_in1 = nt2_o;\n\
+             assign and2_in0 = nt1_o;\nassign and2_in1 = in1;\n\
+             endmodule\n"
+        );
+    }
+
+    #[test]
+    fn flattened_pins_cant_clash_with_other_wires() {
+        let (mut chip, program) = parse_chip("test", "USE STD.NOT AS not\nCHIP not n\n");
+        chip.ast.push(AST::RAIL("n_o".into()));
+        let error = VerilogBackend::compile(chip, program).unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::NameClash("n_o".into(), "n.o".into(), "Verilog")
+        );
+    }
+}
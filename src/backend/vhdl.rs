@@ -0,0 +1,214 @@
+pub struct VhdlBackend;
+use super::netlist::{self, Netlist};
+use super::Backend;
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+impl Backend for VhdlBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let deps = netlist::dependencies(&chip, &program);
+        let mut entities = HashMap::new();
+        for c in deps.iter().copied().chain(std::iter::once(&chip)) {
+            let entity = fold(&ident(&netlist::chip_ident(&c.name)));
+            if let Some(first) = entities.insert(entity, &c.name) {
+                return Err(CompileError::new(
+                    ErrorKind::NameClash(first.clone(), c.name.clone(), "VHDL"),
+                    None,
+                    chip.file_name(),
+                ));
+            }
+        }
+        let mut file = String::new();
+        // Entities are instantiated with `entity work.X`, so they have to be analyzed first
+        for dep in deps {
+            writeln!(file, "{}", gen_entity(dep, &program)?).unwrap();
+        }
+        write!(file, "{}", gen_entity(&chip, &program)?).unwrap();
+        Ok(file)
+    }
+}
+
+fn gen_entity(chip: &Chip, program: &Program) -> Result<String, CompileError> {
+    let netlist = Netlist::new(chip, program);
+    netlist.check_idents("VHDL", |n| fold(&signal(n)))?;
+    let name = ident(&netlist::chip_ident(&chip.name));
+    let mut entity = String::new();
+    writeln!(entity, "library ieee;").unwrap();
+    writeln!(entity, "use ieee.std_logic_1164.all;").unwrap();
+    writeln!(entity).unwrap();
+    writeln!(entity, "entity {} is", name).unwrap();
+    let ports = chip
+        .ins
+        .iter()
+        .map(|i| format!("{} : in std_logic", signal(i)))
+        .chain(
+            chip.outs
+                .iter()
+                .map(|o| format!("{} : out std_logic", signal(o))),
+        )
+        .collect::<Vec<_>>();
+    if !ports.is_empty() {
+        writeln!(entity, "port (\n{}\n);", ports.join(";\n")).unwrap();
+    }
+    writeln!(entity, "end entity;").unwrap();
+    writeln!(entity).unwrap();
+    writeln!(entity, "architecture structural of {} is", name).unwrap();
+
+    if let Some(custom) = &netlist.custom {
+        let code =
+            get_custom_code(custom).ok_or_else(|| netlist::custom_error(chip, custom, "VHDL"))?;
+        writeln!(entity, "begin\n{}", code).unwrap();
+        entity += "end architecture;\n";
+        return Ok(entity);
+    }
+
+    for rail in &netlist.rails {
+        writeln!(entity, "signal {} : std_logic;", signal(rail)).unwrap();
+    }
+    for inst in &netlist.instances {
+        for pin in inst.chip.ins.iter().chain(&inst.chip.outs) {
+            writeln!(entity, "signal {} : std_logic;", signal(&inst.pin(pin))).unwrap();
+        }
+    }
+    writeln!(entity, "begin").unwrap();
+    for inst in &netlist.instances {
+        let map = inst
+            .chip
+            .ins
+            .iter()
+            .chain(&inst.chip.outs)
+            .map(|pin| format!("{} => {}", ident(pin), signal(&inst.pin(pin))))
+            .collect::<Vec<_>>();
+        writeln!(
+            entity,
+            "{} : entity work.{} port map ({});",
+            ident(&inst.name),
+            ident(&netlist::chip_ident(&inst.chip.name)),
+            map.join(", ")
+        )
+        .unwrap();
+    }
+    for reader in netlist.readers() {
+        let value = netlist.or_of(&reader, signal, " or ", "'0'");
+        writeln!(entity, "{} <= {};", signal(&reader), value).unwrap();
+    }
+    entity += "end architecture;\n";
+    Ok(entity)
+}
+
+/// Name of the signal for `a` or `chip.pin`
+fn signal(name: &str) -> String {
+    ident(&name.replace('.', "_"))
+}
+
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+    "abs", "access", "after", "alias", "all", "and", "architecture", "array", "assert",
+    "attribute", "begin", "block", "body", "buffer", "bus", "case", "component", "configuration",
+    "constant", "disconnect", "downto", "else", "elsif", "end", "entity", "exit", "file", "for",
+    "function", "generate", "generic", "group", "guarded", "if", "impure", "in", "inertial",
+    "inout", "is", "label", "library", "linkage", "literal", "loop", "map", "mod", "nand", "new",
+    "next", "nor", "not", "null", "of", "on", "open", "or", "others", "out", "package", "port",
+    "postponed", "procedure", "process", "pure", "range", "record", "register", "reject", "rem",
+    "report", "return", "rol", "ror", "select", "severity", "signal", "shared", "sla", "sll",
+    "sra", "srl", "subtype", "then", "to", "transport", "type", "unaffected", "units", "until",
+    "use", "variable", "wait", "when", "while", "with", "xnor", "xor",
+];
+
+/// Uses an extended identifier for names that are VHDL keywords (in any case) or aren't valid basic identifiers
+fn ident(name: &str) -> String {
+    let lower = name.to_ascii_lowercase();
+    if KEYWORDS.contains(&lower.as_str())
+        || name.starts_with(|c: char| c.is_ascii_digit())
+        || name.contains("__")
+        || name.ends_with('_')
+    {
+        format!("\\{}\\", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// What VHDL compares, basic identifiers are case-insensitive but extended ones aren't
+fn fold(ident: &str) -> String {
+    if ident.starts_with('\\') {
+        ident.to_string()
+    } else {
+        ident.to_ascii_lowercase()
+    }
+}
+
+fn get_custom_code(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("o <= not i;"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn xor_is_an_entity_of_nots_and_ands() {
+        let (chip, program) = parse_chip("xor", include_str!("../xor.chip"));
+        let vhdl = VhdlBackend::compile(chip, program).unwrap();
+        let xor = &vhdl[vhdl.find("entity \\xor\\ is").unwrap()..];
+        assert_eq!(
+            xor,
+            "entity \\xor\\ is\n\
+             port (\nin0 : in std_logic;\nin1 : in std_logic;\n\\out\\ : out std_logic\n);\n\
+             end entity;\n\n\
+             architecture structural of \\xor\\ is\n\
+             signal nt1_i : std_logic;\nsignal nt1_o : std_logic;\n\
+             signal nt2_i : std_logic;\nsignal nt2_o : std_logic;\n\
+             signal and1_in0 : std_logic;\nsignal and1_in1 : std_logic;\n\
+             signal and1_out : std_logic;\n\
+             signal and2_in0 : std_logic;\nsignal and2_in1 : std_logic;\n\
+             signal and2_out : std_logic;\n\
+             begin\n\
+             nt1 : entity work.STD_NOT port map (i => nt1_i, o => nt1_o);\n\
+             nt2 : entity work.STD_NOT port map (i => nt2_i, o => nt2_o);\n\
+             and1 : entity work.STD_AND port map (in0 => and1_in0, in1 => and1_in1, \\out\\ => and1_out);\n\
+             and2 : entity work.STD_AND port map (in0 => and2_in0, in1 => and2_in1, \\out\\ => and2_out);\n\
+             \\out\\ <= and1_out or and2_out;\n\
+             nt1_i <= in0;\nnt2_i <= in1;\n\
+             and1_in0 <= in0;\nand1_in1 <= nt2_o;\n\
+             and2_in0 <= nt1_o;\nand2_in1 <= in1;\n\
+             end architecture;\n"
+        );
+    }
+
+    #[test]
+    fn names_differing_in_case_clash() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN a\nOUT o\nCHIP not NOT1\nCHIP not not1\n\
+             CONNECT a NOT1.i\nCONNECT NOT1.o not1.i\nCONNECT not1.o o\n",
+        );
+        let error = VhdlBackend::compile(chip, program).unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::NameClash("NOT1".into(), "not1".into(), "VHDL")
+        );
+
+        let mut program = Program::new();
+        for name in &["lib.Inv", "lib.inv"] {
+            let chip = Chip::parse(name.to_string(), "IN i\nOUT o\nCONNECT i o\n", &mut program);
+            program.files.insert(name.to_string(), chip.unwrap());
+        }
+        let src = "USE lib.Inv AS a\nUSE lib.inv AS b\nCHIP a x\nCHIP b y\n";
+        let chip = Chip::parse("top".into(), src, &mut program).unwrap();
+        let error = VhdlBackend::compile(chip, program).unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::NameClash("lib.Inv".into(), "lib.inv".into(), "VHDL")
+        );
+    }
+}
@@ -0,0 +1,205 @@
+pub struct YosysBackend;
+use super::netlist::{self, Netlist};
+use super::Backend;
+use crate::error::CompileError;
+use crate::lexer::{Chip, Program};
+
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+
+impl Backend for YosysBackend {
+    type Output = String;
+
+    fn compile(chip: Chip, program: Program) -> Result<String, CompileError> {
+        let mut modules = Map::new();
+        modules.insert(
+            netlist::chip_ident(&chip.name),
+            gen_module(&chip, &program, true)?,
+        );
+        for dep in netlist::dependencies(&chip, &program) {
+            modules.insert(
+                netlist::chip_ident(&dep.name),
+                gen_module(dep, &program, false)?,
+            );
+        }
+        let file = json!({
+            "creator": format!("chip {}", env!("CARGO_PKG_VERSION")),
+            "modules": modules,
+        });
+        Ok(serde_json::to_string_pretty(&file).unwrap() + "\n")
+    }
+}
+
+/// A module being written, with the bit of every signal given a value so far
+struct Module<'a> {
+    netlist: Netlist<'a>,
+    bits: HashMap<String, Value>,
+    /// Bits 0 and 1 are the constants
+    next: u64,
+    cells: Map<String, Value>,
+    netnames: Map<String, Value>,
+}
+
+fn gen_module(chip: &Chip, program: &Program, top: bool) -> Result<Value, CompileError> {
+    let mut module = Module {
+        netlist: Netlist::new(chip, program),
+        bits: HashMap::new(),
+        next: 2,
+        cells: Map::new(),
+        netnames: Map::new(),
+    };
+    let mut ports = Map::new();
+    for i in &chip.ins {
+        let bit = module.driver(i, &mut Vec::new());
+        ports.insert(i.clone(), json!({"direction": "input", "bits": [bit]}));
+    }
+
+    if let Some(custom) = module.netlist.custom.clone() {
+        let cell = get_custom_cell(&custom)
+            .ok_or_else(|| netlist::custom_error(chip, &custom, "Yosys"))?;
+        let a = module.driver(&chip.ins[0], &mut Vec::new());
+        let y = module.fresh(&chip.outs[0]);
+        module
+            .cells
+            .insert(custom, gate(cell, &[("A", a)], ("Y", y.clone())));
+        ports.insert(
+            chip.outs[0].clone(),
+            json!({"direction": "output", "bits": [y]}),
+        );
+    } else {
+        for inst in 0..module.netlist.instances.len() {
+            let (name, inst_chip) = {
+                let inst = &module.netlist.instances[inst];
+                (inst.name.clone(), inst.chip)
+            };
+            let mut directions = Map::new();
+            let mut connections = Map::new();
+            for pin in &inst_chip.ins {
+                let bit = module.reader(&format!("{}.{}", name, pin), &mut Vec::new());
+                directions.insert(pin.clone(), json!("input"));
+                connections.insert(pin.clone(), json!([bit]));
+            }
+            for pin in &inst_chip.outs {
+                let bit = module.driver(&format!("{}.{}", name, pin), &mut Vec::new());
+                directions.insert(pin.clone(), json!("output"));
+                connections.insert(pin.clone(), json!([bit]));
+            }
+            module.cells.insert(
+                name,
+                json!({
+                    "hide_name": 0,
+                    "type": netlist::chip_ident(&inst_chip.name),
+                    "parameters": {},
+                    "attributes": {},
+                    "port_directions": directions,
+                    "connections": connections,
+                }),
+            );
+        }
+        for o in &chip.outs {
+            let bit = module.reader(o, &mut Vec::new());
+            module.name(o, bit.clone());
+            ports.insert(o.clone(), json!({"direction": "output", "bits": [bit]}));
+        }
+    }
+
+    let mut attributes = Map::new();
+    if top {
+        attributes.insert("top".into(), json!(format!("{:032b}", 1)));
+    }
+    Ok(json!({
+        "attributes": attributes,
+        "ports": ports,
+        "cells": module.cells,
+        "netnames": module.netnames,
+    }))
+}
+
+impl<'a> Module<'a> {
+    /// A new bit with `name` in the netnames
+    fn fresh(&mut self, name: &str) -> Value {
+        let bit = json!(self.next);
+        self.next += 1;
+        self.name(name, bit.clone());
+        bit
+    }
+
+    fn name(&mut self, name: &str, bit: Value) {
+        self.netnames.insert(
+            name.into(),
+            json!({"hide_name": 0, "bits": [bit], "attributes": {}}),
+        );
+    }
+
+    /// Bit driven by an IN, a sub-chip output or a RAIL
+    fn driver(&mut self, driver: &str, visiting: &mut Vec<String>) -> Value {
+        if let Some(bit) = self.bits.get(driver) {
+            return bit.clone();
+        }
+        let bit = if self.netlist.rails.iter().any(|r| r == driver) {
+            let bit = self.reader(driver, visiting);
+            self.name(driver, bit.clone());
+            bit
+        } else {
+            self.fresh(driver)
+        };
+        self.bits.insert(driver.into(), bit.clone());
+        bit
+    }
+
+    /// Bit with the value read by `reader`, RAILs are wired-OR so many drivers go through `$_OR_` cells
+    fn reader(&mut self, reader: &str, visiting: &mut Vec<String>) -> Value {
+        // A loop made only of wires doesn't add anything to the OR
+        if visiting.iter().any(|v| v == reader) {
+            return json!("0");
+        }
+        visiting.push(reader.into());
+        let drivers = self
+            .netlist
+            .drivers_of(reader)
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let mut bits = drivers.iter().map(|d| self.driver(d, visiting));
+        let first = bits.next().unwrap_or_else(|| json!("0"));
+        let bits = bits.collect::<Vec<_>>();
+        let bit = bits.into_iter().fold(first, |a, b| {
+            let y = json!(self.next);
+            self.next += 1;
+            let name = format!("$or${}", y);
+            self.cells
+                .insert(name, gate("$_OR_", &[("A", a), ("B", b)], ("Y", y.clone())));
+            y
+        });
+        visiting.pop();
+        bit
+    }
+}
+
+/// One of the Yosys internal gate cells, with 1 bit ports
+fn gate(kind: &str, ins: &[(&str, Value)], (out, y): (&str, Value)) -> Value {
+    let mut directions = Map::new();
+    let mut connections = Map::new();
+    for (pin, bit) in ins {
+        directions.insert(pin.to_string(), json!("input"));
+        connections.insert(pin.to_string(), json!([bit]));
+    }
+    directions.insert(out.into(), json!("output"));
+    connections.insert(out.into(), json!([y]));
+    json!({
+        "hide_name": 1,
+        "type": kind,
+        "parameters": {},
+        "attributes": {},
+        "port_directions": directions,
+        "connections": connections,
+    })
+}
+
+fn get_custom_cell(n: &str) -> Option<&'static str> {
+    match n {
+        "NOT" => Some("$_NOT_"),
+        _ => None,
+    }
+}
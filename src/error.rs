@@ -0,0 +1,290 @@
+use recolored::*;
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+
+/// Owned copy of a pest span, so errors can outlive the source they point into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Self {
+        Self {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+impl Span {
+    /// 1 based line and column of the start of the span
+    pub fn line_col(&self, src: &str) -> (usize, usize) {
+        let before = &src[..self.start.min(src.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        (line, before[line_start..].chars().count() + 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    Parse(String),
+    Io(String),
+    NotFound(String, Vec<PathBuf>),
+    CircularImport(Vec<String>),
+    /// A chip name, from a file name or a USE, that the backends can't turn into identifiers
+    InvalidChipName(String),
+    /// A USE finding another file than the chip already loaded with that name, as `(name, found, first)`
+    ImportConflict(String, PathBuf, PathBuf),
+    AlreadyUsed(String),
+    NotDefined(String),
+    NotAChip(String),
+    NotConnectable(String),
+    BothInput(String, String),
+    BothOutput(String, String),
+    RailToRail(String, String),
+    InvalidCustom(String, &'static str),
+    /// Two names a backend writes as the same identifier, as `(first, second, backend)`
+    NameClash(String, String, &'static str),
+    /// A chip whose layout is bigger than the backend can write, as `(chip, backend)`
+    TooBig(String, &'static str),
+    /// A chip with a feedback loop, for backends that only handle combinational logic.
+    /// The last field is the loop, see `Netlist::find_loop`
+    Loop(String, &'static str, Vec<String>),
+    /// A chip with a feedback loop where only combinational logic makes sense
+    NotCombinational(String, Vec<String>),
+    /// A chip with a truth table too big to print, as `(chip, inputs)`
+    TooManyInputs(String, usize),
+    /// A chip with a feedback loop that never settles for some inputs
+    Oscillation(String),
+    /// A cell in an imported netlist that has no equivalent chip
+    UnknownCell(String),
+    /// A name in an EXPECT that isn't the kind of pin it should be, `IN` or `OUT`
+    NotAPin(String, &'static str),
+    ExpectOutsideTest,
+    /// A statement other than EXPECT after TEST
+    InTest,
+    /// An OUT with another value than the EXPECT says, as `(out, expected, got)`
+    TestFailed(String, bool, bool),
+}
+
+impl ErrorKind {
+    /// Extra information shown after the snippet
+    pub fn notes(&self) -> Vec<String> {
+        match self {
+            Self::NotFound(_, tried) => tried
+                .iter()
+                .map(|dir| {
+                    if dir.as_os_str().is_empty() {
+                        "tried .".into()
+                    } else {
+                        format!("tried {}", dir.display())
+                    }
+                })
+                .collect(),
+            Self::RailToRail(..) => vec!["connect both to the same RAIL instead".into()],
+            Self::NameClash(..) => vec!["rename one of them".into()],
+            Self::InvalidChipName(..) => {
+                vec!["chip names are letters and digits, starting with a letter".into()]
+            }
+            Self::ImportConflict(..) => {
+                vec!["chips are loaded once per name, rename one of the files".into()]
+            }
+            Self::InTest => vec!["the TEST section goes at the end of the chip".into()],
+            Self::TooManyInputs(..) => vec![format!(
+                "truth tables are limited to {} inputs",
+                crate::truth::MAX_INPUTS
+            )],
+            Self::Loop(_, _, path) | Self::NotCombinational(_, path) => {
+                vec![format!("the loop is {}", path.join(" -> "))]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "Parser error: {}", e),
+            Self::Io(e) => write!(f, "Can't read file: {}", e),
+            Self::NotFound(name, _) => {
+                write!(f, "Can't find {} ({}.chip)", name, name.replace('.', "/"))
+            }
+            Self::CircularImport(chain) => write!(f, "Circular import: {}", chain.join(" -> ")),
+            Self::InvalidChipName(name) => write!(f, "{} can't be the name of a chip", name),
+            Self::ImportConflict(name, found, first) => write!(
+                f,
+                "{} is {} here, but it was already loaded from {}",
+                name,
+                found.display(),
+                first.display()
+            ),
+            Self::AlreadyUsed(n) => write!(f, "Name {} is already used", n),
+            Self::NotDefined(n) => write!(f, "Name {} is not defined", n),
+            Self::NotAChip(n) => write!(f, "{} is not a USEd chip", n),
+            Self::NotConnectable(n) => write!(f, "{} can't be connected", n),
+            Self::BothInput(a, b) => write!(f, "Can't connect {} and {}, both are INPUT", a, b),
+            Self::BothOutput(a, b) => write!(f, "Can't connect {} and {}, both are OUTPUT", a, b),
+            Self::RailToRail(a, b) => write!(f, "Don't connect 2 RAILs ({} and {})", a, b),
+            Self::InvalidCustom(c, backend) => {
+                write!(f, "{} is not a valid custom code in {}", c, backend)
+            }
+            Self::NameClash(a, b, backend) => {
+                write!(f, "{} and {} are the same name in {}", a, b, backend)
+            }
+            Self::TooBig(chip, backend) => write!(f, "{} is too big to build in {}", chip, backend),
+            Self::Loop(chip, backend, _) => write!(
+                f,
+                "{} has a feedback loop, which the {} backend can't compile",
+                chip, backend
+            ),
+            Self::NotCombinational(chip, _) => {
+                write!(f, "{} has a feedback loop, so it has no truth table", chip)
+            }
+            Self::TooManyInputs(chip, ins) => {
+                write!(f, "{} has {} inputs, too many for a truth table", chip, ins)
+            }
+            Self::Oscillation(chip) => {
+                write!(f, "{} oscillates, its feedback loops never settle", chip)
+            }
+            Self::UnknownCell(kind) => write!(f, "Can't import cells of type {}", kind),
+            Self::NotAPin(n, kind) => write!(f, "{} is not an {}", n, kind),
+            Self::ExpectOutsideTest => write!(f, "EXPECT can only go in the TEST section"),
+            Self::InTest => write!(f, "Only EXPECT can go in the TEST section"),
+            Self::TestFailed(out, expected, got) => {
+                write!(f, "{} is {}, expected {}", out, *got as u8, *expected as u8)
+            }
+        }
+    }
+}
+
+/// A secondary span pointing at something related to the error, in the same file
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub kind: Box<ErrorKind>,
+    pub span: Option<Span>,
+    pub file: String,
+    pub labels: Vec<Label>,
+}
+
+impl CompileError {
+    pub fn new(kind: ErrorKind, span: Option<Span>, file: String) -> Self {
+        Self {
+            kind: Box::new(kind),
+            span,
+            file,
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn with_label<S: Into<String>>(mut self, span: Span, message: S) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    /// Renders the error like rustc does, with the source lines it points to.
+    /// `sources` maps file names to their contents, if the file isn't there only the message is shown
+    pub fn render(&self, sources: &HashMap<String, String>) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{}{} {}",
+            "error".red().bold(),
+            ":".bold(),
+            self.kind.to_string().bold()
+        )
+        .unwrap();
+        let (span, src) = match (self.span, sources.get(&self.file)) {
+            (Some(span), Some(src)) => (span, src),
+            _ => {
+                writeln!(out, " {} {}", "-->".blue().bold(), self.file).unwrap();
+                self.render_notes(&mut out, 1);
+                return out;
+            }
+        };
+        let (line, col) = span.line_col(src);
+
+        // The primary span is the one without a message
+        let mut marks = vec![(span, None)];
+        marks.extend(
+            self.labels
+                .iter()
+                .map(|l| (l.span, Some(l.message.as_str()))),
+        );
+        marks.sort_by_key(|(s, _)| s.start);
+        let width = marks
+            .iter()
+            .map(|(s, _)| s.line_col(src).0.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(width);
+        let bar = "|".blue().bold();
+
+        writeln!(
+            out,
+            "{}{} {}:{}:{}",
+            pad,
+            "-->".blue().bold(),
+            self.file,
+            line,
+            col
+        )
+        .unwrap();
+        writeln!(out, "{} {}", pad, bar).unwrap();
+        let mut last_line = 0;
+        for (mark, message) in marks {
+            let (l, c) = mark.line_col(src);
+            if l != last_line {
+                if last_line != 0 && l > last_line + 1 {
+                    writeln!(out, "{}", "...".blue().bold()).unwrap();
+                }
+                let text = src.lines().nth(l - 1).unwrap_or("");
+                let num = format!("{:>w$}", l, w = width);
+                writeln!(out, "{} {} {}", num.blue().bold(), bar, text).unwrap();
+                last_line = l;
+            }
+            // Underline until the end of the span or of its first line
+            let len = src[mark.start.min(src.len())..mark.end.min(src.len())]
+                .lines()
+                .next()
+                .map(|s| s.chars().count())
+                .unwrap_or(0)
+                .max(1);
+            let underline = match message {
+                None => "^".repeat(len).red().bold(),
+                Some(m) => format!("{} {}", "-".repeat(len), m).blue().bold(),
+            };
+            writeln!(out, "{} {} {}{}", pad, bar, " ".repeat(c - 1), underline).unwrap();
+        }
+        self.render_notes(&mut out, width + 1);
+        out
+    }
+
+    fn render_notes(&self, out: &mut String, indent: usize) {
+        for note in self.kind.notes() {
+            writeln!(out, "{}{} {}", " ".repeat(indent), "= note:".bold(), note).unwrap();
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.kind)
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -0,0 +1,42 @@
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+use crate::sim::Sim;
+
+/// Runs the TEST section of `chip` in order, so the state of its feedback loops carries from one
+/// EXPECT to the next, returning how many passed and why the others failed
+pub fn run(chip: &Chip, program: &Program) -> Result<(usize, Vec<CompileError>), CompileError> {
+    let mut sim = Sim::new(chip, program)?;
+    let mut passed = 0;
+    let mut failures = Vec::new();
+    for expect in &chip.tests {
+        let ins = chip
+            .ins
+            .iter()
+            .map(|i| expect.ins.iter().any(|(n, v)| n == i && *v))
+            .collect::<Vec<_>>();
+        let outs = match sim.run(&ins) {
+            Ok(outs) => outs,
+            Err(mut e) => {
+                // The state it's left in is meaningless, so the rest can't run
+                e.span = e.span.or(Some(expect.span));
+                failures.push(e);
+                break;
+            }
+        };
+        let before = failures.len();
+        for (name, expected, span) in &expect.outs {
+            let got = outs[chip.outs.iter().position(|o| o == name).unwrap()];
+            if got != *expected {
+                failures.push(CompileError::new(
+                    ErrorKind::TestFailed(name.clone(), *expected, got),
+                    Some(*span),
+                    chip.file_name(),
+                ));
+            }
+        }
+        if failures.len() == before {
+            passed += 1;
+        }
+    }
+    Ok((passed, failures))
+}
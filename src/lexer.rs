@@ -1,18 +1,33 @@
-use pest::iterators::Pairs;
-use pest::{iterators::Pair, Parser};
+use pest::error::{ErrorVariant, InputLocation};
+use pest::iterators::{Pair, Pairs};
+use pest::Parser;
 use pest_derive::Parser;
+use serde_json::{json, Map, Value};
 
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[grammar = "chip.pest"]
 pub struct ChipParser;
 
 use crate::ast::{StatementKind, AST};
+use crate::error::{CompileError, ErrorKind, Span};
+
+/// Environment variable with extra library roots, separated like `PATH`
+pub const CHIP_PATH: &str = "CHIP_PATH";
 
 #[derive(Debug, Clone)]
 pub struct Program {
     pub files: HashMap<String, Chip>,
+    pub search_path: Vec<PathBuf>,
+    /// Source of every parsed file, by `Chip::file_name`, used to render errors
+    pub sources: HashMap<String, String>,
+    /// Chips being parsed, with the canonical path of their files, to find circular imports
+    loading: Vec<(String, PathBuf)>,
 }
 
 impl Program {
@@ -29,28 +44,30 @@ impl Program {
                 ins: vec!["i".into()],
                 outs: vec!["o".into()],
                 name: "STD.NOT".into(),
+                path: None,
+                connect_spans: HashMap::new(),
+                tests: Vec::new(),
             },
         );
-        Self { files: hm }
+        Self {
+            files: hm,
+            search_path: Vec::new(),
+            sources: HashMap::new(),
+            loading: Vec::new(),
+        }
     }
 
     pub fn new() -> Self {
         let mut s = Self::new_simple();
-        let mut s_clone = s.clone();
-        s.files.insert(
-            "STD.AND".into(),
-            Chip::parse("STD.AND".into(), include_str!("and.chip"), &mut s_clone),
-        );
-        s_clone = s.clone();
-        s.files.insert(
-            "STD.XOR".into(),
-            Chip::parse("STD.XOR".into(), include_str!("xor.chip"), &mut s_clone),
-        );
-        s_clone = s.clone();
-        s.files.insert(
-            "STD.NAND".into(),
-            Chip::parse("STD.NAND".into(), include_str!("nand.chip"), &mut s_clone),
-        );
+        for (name, src) in &[
+            ("STD.AND", include_str!("and.chip")),
+            ("STD.XOR", include_str!("xor.chip")),
+            ("STD.NAND", include_str!("nand.chip")),
+        ] {
+            let chip = Chip::parse(name.to_string(), src, &mut s)
+                .unwrap_or_else(|e| unreachable!("Standard chip {} failed: {}", name, e[0]));
+            s.files.insert(name.to_string(), chip);
+        }
         s
     }
 
@@ -62,10 +79,169 @@ impl Program {
         }
     }
 
-    pub fn resolve(&mut self, name: &String) {
-        if !self.files.contains_key(name) {
-            todo!("resolve import and insert into files") // TODO resolve import and insert into files
+    /// The chip being compiled and every chip loaded, for tools that want to inspect them
+    pub fn to_json(&self, top: &Chip) -> Value {
+        let mut names = self.files.keys().collect::<Vec<_>>();
+        names.sort();
+        let files = names
+            .into_iter()
+            .map(|n| (n.clone(), self.files[n].to_json(self)))
+            .collect::<Map<_, _>>();
+        json!({
+            "chip": top.to_json(self),
+            "files": files,
+            "search_path": self.search_path,
+        })
+    }
+
+    pub fn add_library_root<P: Into<PathBuf>>(&mut self, root: P) {
+        self.search_path.push(root.into());
+    }
+
+    /// Appends the library roots listed in the `CHIP_PATH` environment variable
+    pub fn add_env_library_roots(&mut self) {
+        if let Some(paths) = env::var_os(CHIP_PATH) {
+            self.search_path.extend(env::split_paths(&paths));
+        }
+    }
+
+    /// Loads the chip `name` (a dotted path like `my.lib.adder`) if it isn't loaded yet.
+    /// The path is looked up as `my/lib/adder.chip` relative to the importing file,
+    /// and then relative to every library root in `search_path`, in order.
+    /// Chips are loaded once per name, so a name finding another file than the first time is an error
+    ///
+    /// Errors found while looking the file up have no span, the caller should add the one of the `USE`
+    pub fn resolve(&mut self, name: &String, from: Option<&Path>) -> Result<(), Vec<CompileError>> {
+        let from_file = from
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<main>".into());
+        let error = |kind| vec![CompileError::new(kind, None, from_file.clone())];
+        if !is_chip_name(name) {
+            return Err(error(ErrorKind::InvalidChipName(name.clone())));
+        }
+        let loaded = match self.files.get(name) {
+            // Built in, like the STD chips
+            Some(Chip { path: None, .. }) => return Ok(()),
+            Some(Chip { path: Some(p), .. }) => Some(canonical(p)),
+            None => None,
+        };
+        let mut rel = PathBuf::new();
+        rel.extend(name.split('.'));
+        rel.set_extension("chip");
+        let importer_dir = from
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let dirs = std::iter::once(importer_dir)
+            .chain(self.search_path.iter().cloned())
+            .collect::<Vec<_>>();
+        let path = match dirs.iter().map(|d| d.join(&rel)).find(|p| p.is_file()) {
+            Some(p) => p,
+            None => return Err(error(ErrorKind::NotFound(name.clone(), dirs))),
+        };
+        let found = canonical(&path);
+        if self.loading.iter().any(|(_, p)| *p == found) {
+            let mut chain = self
+                .loading
+                .iter()
+                .map(|(n, _)| n.clone())
+                .collect::<Vec<_>>();
+            chain.push(name.clone());
+            return Err(error(ErrorKind::CircularImport(chain)));
+        }
+        let first = loaded.or_else(|| {
+            self.loading
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, p)| p.clone())
+        });
+        match first {
+            Some(first) if first == found => Ok(()),
+            Some(first) => Err(error(ErrorKind::ImportConflict(name.clone(), found, first))),
+            None => {
+                let chip = Chip::parse_file(name.clone(), &path, self)?;
+                self.files.insert(name.clone(), chip);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether `name` can be used as the name of a chip in every backend: parts made of ASCII
+/// letters and digits separated by `.`, starting with a letter
+pub fn is_chip_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// `src` parsed as the chip `name`, with the program holding the chips it uses
+#[cfg(test)]
+pub fn parse_chip(name: &str, src: &str) -> (Chip, Program) {
+    let mut program = Program::new();
+    let chip = Chip::parse(name.into(), src, &mut program).unwrap();
+    (chip, program)
+}
+
+/// Path used to tell if two paths are the same file
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn parse_message(variant: &ErrorVariant<Rule>) -> String {
+    let list = |rules: &[Rule]| {
+        let mut names = Vec::new();
+        for rule in rules {
+            let name = describe(rule);
+            if !names.contains(&name) {
+                names.push(name);
+            }
         }
+        match names.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+            None => String::new(),
+        }
+    };
+    match variant {
+        ErrorVariant::ParsingError {
+            positives,
+            negatives,
+        } => match (positives.is_empty(), negatives.is_empty()) {
+            (false, false) => format!(
+                "unexpected {}; expected {}",
+                list(negatives),
+                list(positives)
+            ),
+            (false, true) => format!("expected {}", list(positives)),
+            (true, false) => format!("unexpected {}", list(negatives)),
+            (true, true) => "unknown parsing error".into(),
+        },
+        ErrorVariant::CustomError { message } => message.clone(),
+    }
+}
+
+/// What a grammar rule is called in errors, the statements by their keyword
+fn describe(rule: &Rule) -> &'static str {
+    match rule {
+        // Lines are parsed on their own, so the end of the input is the end of the line
+        Rule::EOI | Rule::EOL => "end of line",
+        Rule::NAME => "a name",
+        Rule::PATH => "a chip path like my.lib.adder",
+        Rule::DEFINED_NAME => "a name or instance.pin",
+        Rule::USE => "USE",
+        Rule::IN => "IN",
+        Rule::OUT => "OUT",
+        Rule::RAIL => "RAIL",
+        Rule::CHIP_DEF => "CHIP",
+        Rule::CONNECT => "CONNECT",
+        Rule::TEST => "TEST",
+        Rule::EXPECT => "EXPECT",
+        Rule::EXPECT_INS | Rule::EXPECT_OUTS | Rule::VALUE => "a value like in0=1",
+        Rule::BIT => "0 or 1",
+        // SINGLE_STATEMENT, when nothing matches the line
+        _ => "a statement",
     }
 }
 
@@ -75,12 +251,48 @@ pub struct Chip {
     pub ins: Vec<String>,
     pub outs: Vec<String>,
     pub name: String,
+    pub path: Option<PathBuf>,
+    /// Where each CONNECT is written, by its two names in the order of the source.
+    /// Empty for chips that weren't parsed from a file
+    pub connect_spans: HashMap<(String, String), Span>,
+    /// The TEST section, run in order by `chip test`
+    pub tests: Vec<Expect>,
 }
 
+/// A line of the TEST section
+#[derive(Debug, Clone)]
+pub struct Expect {
+    /// Values of the INs, the ones not given are 0
+    pub ins: Vec<(String, bool)>,
+    /// Values the OUTs should have after running, with where each one is written
+    pub outs: Vec<(String, bool, Span)>,
+    pub span: Span,
+}
+
+impl Expect {
+    fn to_json(&self) -> Value {
+        let ins = self
+            .ins
+            .iter()
+            .map(|(n, v)| (n.clone(), json!(v)))
+            .collect::<Map<_, _>>();
+        let outs = self
+            .outs
+            .iter()
+            .map(|(n, v, _)| (n.clone(), json!(v)))
+            .collect::<Map<_, _>>();
+        json!({"ins": ins, "outs": outs})
+    }
+}
+
+/// What a connected name does on its wire
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum InOut {
+    /// Reads from the wire: an OUT or the input of a sub-chip
     IN,
+    /// Drives the wire: an IN or the output of a sub-chip
     OUT,
+    RAIL,
 }
 
 impl Chip {
@@ -90,278 +302,581 @@ impl Chip {
             ins: Vec::new(),
             outs: Vec::new(),
             name,
+            path: None,
+            connect_spans: HashMap::new(),
+            tests: Vec::new(),
         }
     }
 
-    pub fn parse(name: String, src: &str, program: &mut Program) -> Self {
-        let mut s = Self::new(name.clone());
+    /// Name used to refer to this chip's source in errors
+    pub fn file_name(&self) -> String {
+        match &self.path {
+            Some(p) => p.display().to_string(),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Interface and statements of the chip, with the file every USE was loaded from
+    /// (`null` for the STD chips, which are built in)
+    pub fn to_json(&self, program: &Program) -> Value {
+        let rails = self
+            .ast
+            .iter()
+            .filter_map(|s| match s {
+                AST::RAIL(r) => Some(r.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let uses = self
+            .ast
+            .iter()
+            .filter_map(|s| match s {
+                AST::USE(path, alias) => Some(json!({
+                    "alias": alias,
+                    "path": path,
+                    "file": program.files.get(path).and_then(|c| c.path.as_ref()),
+                })),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "name": self.name,
+            "file": self.path,
+            "ins": self.ins,
+            "outs": self.outs,
+            "rails": rails,
+            "uses": uses,
+            "statements": self.ast.iter().map(AST::to_json).collect::<Vec<_>>(),
+            "tests": self.tests.iter().map(Expect::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn parse_file(
+        name: String,
+        path: &Path,
+        program: &mut Program,
+    ) -> Result<Self, Vec<CompileError>> {
+        let mut s = Self::new(name);
+        s.path = Some(path.to_path_buf());
+        let src = fs::read_to_string(path)
+            .map_err(|e| vec![s.error(ErrorKind::Io(e.to_string()), None)])?;
+        program.loading.push((s.name.clone(), canonical(path)));
+        let res = s.lex_src(&src, program);
+        program.loading.pop();
+        res?;
+        Ok(s)
+    }
+
+    pub fn parse(
+        name: String,
+        src: &str,
+        program: &mut Program,
+    ) -> Result<Self, Vec<CompileError>> {
+        let mut s = Self::new(name);
+        s.lex_src(src, program)?;
+        Ok(s)
+    }
+
+    fn error(&self, kind: ErrorKind, span: Option<Span>) -> CompileError {
+        CompileError::new(kind, span, self.file_name())
+    }
+
+    /// `offset` is where the parsed text starts in the file
+    fn parse_error(&self, e: pest::error::Error<Rule>, offset: usize) -> CompileError {
+        let (start, end) = match e.location {
+            InputLocation::Pos(p) => (p, p),
+            InputLocation::Span(span) => span,
+        };
+        let span = Span {
+            start: offset + start,
+            end: offset + end,
+        };
+        self.error(ErrorKind::Parse(parse_message(&e.variant)), Some(span))
+    }
+
+    fn lex_src(&mut self, src: &str, program: &mut Program) -> Result<(), Vec<CompileError>> {
+        program.sources.insert(self.file_name(), src.to_string());
         let file = match ChipParser::parse(Rule::CHIP, src) {
             Ok(mut p) => p.next().unwrap(),
-            Err(e) => {
-                //print_e(&e, src);
-                panic!("Parser error:\n{}", e);
-            }
+            Err(e) => return Err(vec![self.parse_error(e, 0)]),
         };
-        s.lex(file.into_inner(), program);
-        s
+        self.lex(file.into_inner(), program)
     }
 
-    pub fn lex(&mut self, p: Pairs<Rule>, program: &mut Program) {
-        let mut var: HashMap<String, StatementKind> = HashMap::new();
-        let mut rail: HashMap<String, Vec<(InOut, String)>> = HashMap::new();
-        let mut chip_defs: HashMap<String, String> = HashMap::new();
-        let mut uses: HashMap<String, String> = HashMap::new();
+    /// Lexes every statement, skipping the wrong ones so all the errors in the file are found
+    pub fn lex(&mut self, p: Pairs<Rule>, program: &mut Program) -> Result<(), Vec<CompileError>> {
+        let mut scope = Scope::default();
+        let mut errors = Vec::new();
         for pair in p {
-            let span = pair.as_span();
-            match pair.as_rule() {
-                Rule::IN => {
-                    let name = pair.into_inner().next().unwrap().as_str().to_string();
-                    if !var.contains_key(&name) {
+            self.lex_statement(pair, &mut scope, program, &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn lex_statement(
+        &mut self,
+        pair: Pair<Rule>,
+        scope: &mut Scope,
+        program: &mut Program,
+        errors: &mut Vec<CompileError>,
+    ) {
+        if let Some(test) = scope.test {
+            if !matches!(
+                pair.as_rule(),
+                Rule::EXPECT | Rule::TEST | Rule::ERROR | Rule::EOI
+            ) {
+                errors.push(
+                    self.error(ErrorKind::InTest, Some(Span::from(pair.as_span())))
+                        .with_label(test, "the TEST section starts here"),
+                );
+                return;
+            }
+        }
+        match pair.as_rule() {
+            Rule::IN | Rule::OUT | Rule::RAIL => {
+                let rule = pair.as_rule();
+                let name_pair = pair.into_inner().next().unwrap();
+                let span = Span::from(name_pair.as_span());
+                let name = name_pair.as_str().to_string();
+                let e = match scope.var.entry(name.clone()) {
+                    Entry::Vacant(e) => e,
+                    Entry::Occupied(e) => {
+                        errors.push(self.already_used(name, span, e.get().1));
+                        return;
+                    }
+                };
+                match rule {
+                    Rule::IN => {
                         self.ast.push(AST::IN(name.clone()));
-                        self.ins.push(name.clone());
-                        var.insert(name, StatementKind::IN);
-                    } else {
-                        panic!("Name {} is already used", name);
+                        self.ins.push(name);
+                        e.insert((StatementKind::IN, span));
                     }
-                }
-                Rule::OUT => {
-                    let name = pair.into_inner().next().unwrap().as_str().to_string();
-                    if !var.contains_key(&name) {
+                    Rule::OUT => {
                         self.ast.push(AST::OUT(name.clone()));
-                        self.outs.push(name.clone());
-                        var.insert(name, StatementKind::OUT);
-                    } else {
-                        panic!("Name {} is already used", name);
+                        self.outs.push(name);
+                        e.insert((StatementKind::OUT, span));
                     }
-                }
-                Rule::RAIL => {
-                    let name = pair.into_inner().next().unwrap().as_str().to_string();
-                    if !var.contains_key(&name) {
+                    _ => {
                         self.ast.push(AST::RAIL(name.clone()));
-                        var.insert(name.clone(), StatementKind::RAIL);
-                        rail.insert(name, Vec::new());
-                    } else {
-                        panic!("Name {} is already used", name);
+                        e.insert((StatementKind::RAIL, span));
+                        scope.rail.insert(name, Vec::new());
                     }
                 }
-                Rule::CONNECT => {
-                    let mut inner = pair.into_inner();
-                    let name1 = inner
-                        .next()
-                        .unwrap()
-                        .as_str()
-                        .to_string()
-                        .split(".")
-                        .map(|x| x.to_string())
-                        .collect::<Vec<_>>();
-                    let name2 = inner
-                        .next()
-                        .unwrap()
-                        .as_str()
-                        .to_string()
-                        .split(".")
-                        .map(|x| x.to_string())
-                        .collect::<Vec<_>>();
-                    // TODO SPLIT DEFINED NAMES INTO VECS
-                    if var.contains_key(&name1[0]) {
-                        if var.contains_key(&name2[0]) {
-                            let kind1 = var.get(&name1[0]).unwrap();
-                            let kind2 = var.get(&name2[0]).unwrap();
-                            if kind1 == &StatementKind::RAIL {
-                                if kind2 == &StatementKind::RAIL {
-                                    panic!(
-                                        "Don't connect 2 RAILs {:?}",
-                                        span.start_pos().line_col()
-                                    )
-                                } else {
-                                    if kind2 == &StatementKind::CHIP {
-                                        let chip =
-                                            program.get_chip(chip_defs.get(&name2[0]).unwrap());
-                                        if chip.ins.contains(&name2[1]) {
-                                            let r =
-                                                rail.get_mut(&name1[0]).expect("RAIL not found");
-                                            r.push((InOut::IN, name2.join(".")));
-                                        // IN
-                                        } else if chip.outs.contains(&name2[1]) {
-                                            let r =
-                                                rail.get_mut(&name1[0]).expect("RAIL not found");
-                                            r.push((InOut::OUT, name2.join(".")));
-                                        // OUT
-                                        } else {
-                                            panic!("Name {} is not defined", name2.join("."))
-                                        }
-                                    } else if kind2 == &StatementKind::IN {
-                                        let r = rail.get_mut(&name1[0]).expect("RAIL not found");
-                                        r.push((InOut::IN, name2.join(".")));
-                                    } else if kind2 == &StatementKind::OUT {
-                                        let r = rail.get_mut(&name1[0]).expect("RAIL not found");
-                                        r.push((InOut::OUT, name2.join(".")));
-                                    } else {
-                                        unreachable!()
-                                    }
-                                }
-                            } else if kind2 == &StatementKind::RAIL {
-                                if kind1 == &StatementKind::CHIP {
-                                    let chip = program.get_chip(chip_defs.get(&name1[0]).unwrap());
-                                    if chip.ins.contains(&name1[1]) {
-                                        let r = rail.get_mut(&name2[0]).expect("RAIL not found");
-                                        r.push((InOut::IN, name1.join(".")));
-                                    // IN
-                                    } else if chip.outs.contains(&name1[1]) {
-                                        let r = rail.get_mut(&name2[0]).expect("RAIL not found");
-                                        r.push((InOut::OUT, name1.join(".")));
-                                    // OUT
-                                    } else {
-                                        panic!("Name {} is not defined", name1.join("."))
-                                    }
-                                } else if kind1 == &StatementKind::IN {
-                                    let r = rail.get_mut(&name2[0]).expect("RAIL not found");
-                                    r.push((InOut::IN, name1.join(".")));
-                                } else if kind1 == &StatementKind::OUT {
-                                    let r = rail.get_mut(&name2[0]).expect("RAIL not found");
-                                    r.push((InOut::OUT, name1.join(".")));
-                                } else {
-                                    unreachable!()
-                                }
-                            } else if kind1 == &StatementKind::CHIP {
-                                let chip1 = program.get_chip(chip_defs.get(&name1[0]).unwrap());
-                                let io = if chip1.ins.contains(&name1[1]) {
-                                    InOut::IN
-                                } else if chip1.outs.contains(&name1[1]) {
-                                    InOut::OUT
-                                } else {
-                                    panic!("Name {} not found", name1.join("."))
-                                };
-
-                                if kind2 == &StatementKind::CHIP {
-                                    let chip2 = program.get_chip(chip_defs.get(&name2[0]).unwrap());
-                                    if chip2.ins.contains(&name2[1]) {
-                                        //let r = rail.get_mut(&name1[0]).expect("RAIL not found");
-                                        //r.push((InOut::IN, name2.join(".")));
-                                        if io == InOut::IN {
-                                            panic!(
-                                                "Can't connect {} and {}, both are INPUT",
-                                                name1.join("."),
-                                                name2.join(".")
-                                            )
-                                        }
-                                    // IN
-                                    } else if chip2.outs.contains(&name2[1]) {
-                                        if io == InOut::OUT {
-                                            panic!(
-                                                "Can't connect {} and {}, both are OUTPUT",
-                                                name1.join("."),
-                                                name2.join(".")
-                                            )
-                                        }
-                                    // OUT
-                                    } else {
-                                        panic!("Name {} is not defined", name2.join("."))
-                                    }
-                                } else if kind2 == &StatementKind::IN {
-                                    if io == InOut::OUT {
-                                        panic!(
-                                            "Can't connect {} and {}, both are OUTPUT",
-                                            name1.join("."),
-                                            name2.join(".")
-                                        )
-                                    }
-                                } else if kind2 == &StatementKind::OUT {
-                                    if io == InOut::IN {
-                                        panic!(
-                                            "Can't connect {} and {}, both are INPUT",
-                                            name1.join("."),
-                                            name2.join(".")
-                                        )
-                                    }
-                                }
-                            } else if kind2 == &StatementKind::CHIP {
-                                let chip1 = program.get_chip(chip_defs.get(&name2[0]).unwrap());
-                                let io = if chip1.ins.contains(&name2[1]) {
-                                    InOut::IN
-                                } else if chip1.outs.contains(&name2[1]) {
-                                    InOut::OUT
+            }
+            Rule::CONNECT => {
+                let span = Span::from(pair.as_span());
+                let mut inner = pair.into_inner();
+                let pair1 = inner.next().unwrap();
+                let pair2 = inner.next().unwrap();
+                let (span1, span2) = (Span::from(pair1.as_span()), Span::from(pair2.as_span()));
+                let name1 = pair1.as_str().to_string();
+                let name2 = pair2.as_str().to_string();
+                let io1 = self.direction(&name1, span1, scope, program);
+                let io2 = self.direction(&name2, span2, scope, program);
+                let (io1, io2) = match (io1, io2) {
+                    (Ok(Some(io1)), Ok(Some(io2))) => (io1, io2),
+                    (io1, io2) => {
+                        // Either one is wrong or it belongs to a chip that couldn't be loaded
+                        errors.extend(io1.err());
+                        errors.extend(io2.err());
+                        return;
+                    }
+                };
+                let conflict = match (io1, io2) {
+                    (InOut::RAIL, InOut::RAIL) => Some((
+                        ErrorKind::RailToRail(name1.clone(), name2.clone()),
+                        "this is also a RAIL",
+                    )),
+                    (InOut::IN, InOut::IN) => Some((
+                        ErrorKind::BothInput(name1.clone(), name2.clone()),
+                        "this also reads from the wire",
+                    )),
+                    (InOut::OUT, InOut::OUT) => Some((
+                        ErrorKind::BothOutput(name1.clone(), name2.clone()),
+                        "this also drives the wire",
+                    )),
+                    (InOut::RAIL, io) => {
+                        scope
+                            .rail
+                            .get_mut(&name1)
+                            .expect("RAIL not found")
+                            .push((io, name2.clone()));
+                        None
+                    }
+                    (io, InOut::RAIL) => {
+                        scope
+                            .rail
+                            .get_mut(&name2)
+                            .expect("RAIL not found")
+                            .push((io, name1.clone()));
+                        None
+                    }
+                    _ => None,
+                };
+                match conflict {
+                    Some((kind, label)) => {
+                        errors.push(self.error(kind, Some(span1)).with_label(span2, label))
+                    }
+                    None => {
+                        self.connect_spans
+                            .entry((name1.clone(), name2.clone()))
+                            .or_insert(span);
+                        self.ast.push(AST::CONNECT(name1, name2));
+                    }
+                }
+            }
+            Rule::CHIP_DEF => {
+                let mut inner = pair.into_inner();
+                let use_pair = inner.next().unwrap();
+                let define_pair = inner.next().unwrap();
+                let use_span = Span::from(use_pair.as_span());
+                let define_span = Span::from(define_pair.as_span());
+                let chip_use_name = use_pair.as_str().to_string();
+                let define_name = define_pair.as_str().to_string();
+                if let Some((_, decl)) = scope.var.get(&define_name) {
+                    errors.push(self.already_used(define_name, define_span, *decl));
+                    return;
+                }
+                // The instance is declared even if its chip is wrong, so its uses aren't reported too
+                let path = match (
+                    scope.var.get(&chip_use_name),
+                    scope.uses.get(&chip_use_name),
+                ) {
+                    (Some(_), Some(path)) => path.clone(),
+                    (Some((kind, decl)), None) => {
+                        errors.push(
+                            self.error(ErrorKind::NotAChip(chip_use_name.clone()), Some(use_span))
+                                .with_label(*decl, format!("declared here as {:?}", kind)),
+                        );
+                        None
+                    }
+                    (None, _) => {
+                        errors.push(
+                            self.error(
+                                ErrorKind::NotDefined(chip_use_name.clone()),
+                                Some(use_span),
+                            ),
+                        );
+                        None
+                    }
+                };
+                if path.is_some() {
+                    self.ast.push(AST::CHIP(chip_use_name, define_name.clone()));
+                }
+                scope
+                    .var
+                    .insert(define_name.clone(), (StatementKind::CHIP, define_span));
+                scope.chip_defs.insert(define_name, path);
+            }
+            Rule::USE => {
+                let mut inner = pair.into_inner();
+                let path_pair = inner.next().unwrap();
+                let alias_pair = inner.next().unwrap();
+                let path_span = Span::from(path_pair.as_span());
+                let alias_span = Span::from(alias_pair.as_span());
+                let chip_path = path_pair.as_str().to_string();
+                let alias = alias_pair.as_str().to_string();
+                if let Some((_, decl)) = scope.var.get(&alias) {
+                    errors.push(self.already_used(alias, alias_span, *decl));
+                    return;
+                }
+                let loaded = match program.resolve(&chip_path, self.path.as_deref()) {
+                    Ok(()) => {
+                        self.ast.push(AST::USE(chip_path.clone(), alias.clone()));
+                        Some(chip_path)
+                    }
+                    Err(errs) => {
+                        let file_name = self.file_name();
+                        errors.extend(errs.into_iter().map(|mut e| {
+                            if e.span.is_none() && e.file == file_name {
+                                e.span = Some(path_span);
+                            }
+                            e
+                        }));
+                        None
+                    }
+                };
+                scope
+                    .var
+                    .insert(alias.clone(), (StatementKind::USE, alias_span));
+                scope.uses.insert(alias, loaded);
+            }
+            Rule::TEST => {
+                scope.test.get_or_insert(Span::from(pair.as_span()));
+            }
+            Rule::EXPECT => {
+                let span = Span::from(pair.as_span());
+                if scope.test.is_none() {
+                    errors.push(self.error(ErrorKind::ExpectOutsideTest, Some(span)));
+                    return;
+                }
+                let mut inner = pair.into_inner();
+                let ins = inner.next().unwrap();
+                let outs = inner.next().unwrap();
+                let mut expect = Expect {
+                    ins: Vec::new(),
+                    outs: Vec::new(),
+                    span,
+                };
+                // Where each pin got its value, a second one would silently win
+                let mut given: HashMap<String, Span> = HashMap::new();
+                for (values, kind) in [(ins, StatementKind::IN), (outs, StatementKind::OUT)] {
+                    for value in values.into_inner() {
+                        let value_span = Span::from(value.as_span());
+                        let mut inner = value.into_inner();
+                        let name_pair = inner.next().unwrap();
+                        let name = name_pair.as_str().to_string();
+                        let bit = inner.next().unwrap().as_str() == "1";
+                        match scope.var.get(&name) {
+                            Some((k, _)) if *k == kind => {}
+                            Some((k, decl)) => {
+                                let pin = if kind == StatementKind::IN {
+                                    "IN"
                                 } else {
-                                    panic!("Name {} not found", name2.join("."))
+                                    "OUT"
                                 };
-
-                                if kind1 == &StatementKind::IN {
-                                    if io == InOut::OUT {
-                                        panic!(
-                                            "Can't connect {} and {}, both are OUTPUT",
-                                            name1.join("."),
-                                            name2.join(".")
-                                        )
-                                    }
-                                } else if kind1 == &StatementKind::OUT {
-                                    if io == InOut::IN {
-                                        panic!(
-                                            "Can't connect {} and {}, both are INPUT",
-                                            name1.join("."),
-                                            name2.join(".")
-                                        )
-                                    }
-                                }
-                            } else if kind1 == &StatementKind::IN {
-                                if kind2 == &StatementKind::IN {
-                                    panic!(
-                                        "Can't connect {} and {}, both are OUTPUT",
-                                        name1.join("."),
-                                        name2.join(".")
-                                    )
-                                }
-                            } else if kind1 == &StatementKind::OUT {
-                                if kind2 == &StatementKind::OUT {
-                                    panic!(
-                                        "Can't connect {} and {}, both are INPUT",
-                                        name1.join("."),
-                                        name2.join(".")
+                                errors.push(
+                                    self.error(
+                                        ErrorKind::NotAPin(name, pin),
+                                        Some(Span::from(name_pair.as_span())),
                                     )
-                                }
+                                    .with_label(*decl, format!("declared here as {:?}", k)),
+                                );
+                                continue;
+                            }
+                            None => {
+                                errors.push(self.error(
+                                    ErrorKind::NotDefined(name),
+                                    Some(Span::from(name_pair.as_span())),
+                                ));
+                                continue;
                             }
-                        } else {
-                            panic!("Name {} is not defined", name2[0]);
                         }
-                    } else {
-                        panic!("Name {} is not defined", name1[0]);
-                    }
-                    self.ast
-                        .push(AST::CONNECT(name1.join("."), name2.join(".")))
-                }
-                Rule::CHIP_DEF => {
-                    let mut inner = pair.into_inner();
-                    let chip_use_name = inner.next().unwrap().as_str().to_string();
-                    let define_name = inner.next().unwrap().as_str().to_string();
-                    if var.contains_key(&chip_use_name) {
-                        if !var.contains_key(&define_name) {
-                            self.ast
-                                .push(AST::CHIP(chip_use_name.clone(), define_name.clone()));
-                            var.insert(define_name.clone(), StatementKind::CHIP);
-                            chip_defs
-                                .insert(define_name, uses.get(&chip_use_name).unwrap().clone());
+                        let name_span = Span::from(name_pair.as_span());
+                        if let Some(first) = given.get(&name) {
+                            errors.push(
+                                self.error(ErrorKind::AlreadyUsed(name), Some(name_span))
+                                    .with_label(*first, "first given here"),
+                            );
+                            continue;
+                        }
+                        given.insert(name.clone(), name_span);
+                        if kind == StatementKind::IN {
+                            expect.ins.push((name, bit));
                         } else {
-                            panic!("Name {} is already used", define_name);
+                            expect.outs.push((name, bit, value_span));
                         }
-                    } else {
-                        panic!("Name {} is not defined", chip_use_name);
                     }
                 }
-                Rule::USE => {
-                    let mut inner = pair.into_inner();
-                    let chip_path = inner.next().unwrap().as_str().to_string();
-                    let alias = inner.next().unwrap().as_str().to_string();
-                    if !var.contains_key(&alias) {
-                        program.resolve(&chip_path);
-                        self.ast.push(AST::USE(chip_path.clone(), alias.clone()));
-                        var.insert(alias.clone(), StatementKind::USE);
-                        uses.insert(alias, chip_path);
-                    } else {
-                        panic!("Name {} is already used", alias);
-                    }
+                self.tests.push(expect);
+            }
+            Rule::ERROR => {
+                let offset = pair.as_span().start();
+                match ChipParser::parse(Rule::SINGLE_STATEMENT, pair.as_str()) {
+                    Err(e) => errors.push(self.parse_error(e, offset)),
+                    Ok(_) => errors.push(self.error(
+                        ErrorKind::Parse("expected a single statement in this line".into()),
+                        Some(Span::from(pair.as_span())),
+                    )),
+                }
+            }
+            Rule::EOI => {}
+            x => unreachable!("Rule shouldn't be here {:?}", x),
+        }
+    }
+
+    fn already_used(&self, name: String, span: Span, first: Span) -> CompileError {
+        self.error(ErrorKind::AlreadyUsed(name), Some(span))
+            .with_label(first, "first declared here")
+    }
+
+    /// Finds out if `name` (`a` or `chip.pin`) reads from or drives the wire it's connected to,
+    /// `None` if it's a pin of a chip that failed to load
+    fn direction(
+        &self,
+        name: &str,
+        span: Span,
+        scope: &Scope,
+        program: &Program,
+    ) -> Result<Option<InOut>, CompileError> {
+        let mut parts = name.splitn(2, '.');
+        let base = parts.next().unwrap();
+        let (kind, decl) = match scope.var.get(base) {
+            Some(v) => v,
+            None => return Err(self.error(ErrorKind::NotDefined(base.into()), Some(span))),
+        };
+        match (kind, parts.next()) {
+            (StatementKind::IN, None) => Ok(Some(InOut::OUT)),
+            (StatementKind::OUT, None) => Ok(Some(InOut::IN)),
+            (StatementKind::RAIL, None) => Ok(Some(InOut::RAIL)),
+            (StatementKind::CHIP, Some(pin)) => {
+                let chip = match &scope.chip_defs[base] {
+                    Some(path) => program.get_chip(path),
+                    None => return Ok(None),
+                };
+                if chip.ins.iter().any(|i| i == pin) {
+                    Ok(Some(InOut::IN))
+                } else if chip.outs.iter().any(|o| o == pin) {
+                    Ok(Some(InOut::OUT))
+                } else {
+                    Err(self
+                        .error(ErrorKind::NotDefined(name.into()), Some(span))
+                        .with_label(
+                            *decl,
+                            format!(
+                                "{} is a {} (IN {}; OUT {})",
+                                base,
+                                chip.name,
+                                chip.ins.join(", "),
+                                chip.outs.join(", ")
+                            ),
+                        ))
                 }
-                Rule::EOI => {}
-                x => unreachable!("Rule shouldn't be here {:?}", x),
             }
+            _ => Err(self
+                .error(ErrorKind::NotConnectable(name.into()), Some(span))
+                .with_label(*decl, format!("declared here as {:?}", kind))),
         }
     }
 }
+
+/// Names declared so far in the chip being lexed
+#[derive(Default)]
+struct Scope {
+    var: HashMap<String, (StatementKind, Span)>,
+    rail: HashMap<String, Vec<(InOut, String)>>,
+    /// Chip path of every instance, `None` if it couldn't be loaded
+    chip_defs: HashMap<String, Option<String>>,
+    /// Chip path of every USE alias, `None` if it couldn't be loaded
+    uses: HashMap<String, Option<String>>,
+    /// Where the TEST section starts
+    test: Option<Span>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(src: &str) -> Vec<String> {
+        Chip::parse("test".into(), src, &mut Program::new())
+            .unwrap_err()
+            .iter()
+            .map(|e| e.kind.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parse_errors_name_what_was_expected() {
+        assert_eq!(
+            errors("IN a\nIN b c\n"),
+            ["Parser error: expected end of line"]
+        );
+        assert_eq!(errors("CHIP x\n"), ["Parser error: expected a name"]);
+        assert_eq!(
+            errors("TEST\nEXPECT a=2 => b=1\n"),
+            ["Parser error: expected 0 or 1"]
+        );
+        assert_eq!(errors("FOO x\n"), ["Parser error: expected a statement"]);
+    }
+
+    #[test]
+    fn json_lists_the_chip_and_every_loaded_file() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.NOT AS not\nIN a\nOUT o\nRAIL r\nCHIP not n\n\
+             CONNECT a n.i\nCONNECT n.o r\nCONNECT r o\n",
+        );
+        let json = program.to_json(&chip);
+        assert_eq!(
+            json["chip"],
+            json!({
+                "name": "test",
+                "file": null,
+                "ins": ["a"],
+                "outs": ["o"],
+                "rails": ["r"],
+                "uses": [{"alias": "not", "path": "STD.NOT", "file": null}],
+                "statements": [
+                    {"kind": "USE", "path": "STD.NOT", "alias": "not"},
+                    {"kind": "IN", "name": "a"},
+                    {"kind": "OUT", "name": "o"},
+                    {"kind": "RAIL", "name": "r"},
+                    {"kind": "CHIP", "alias": "not", "name": "n"},
+                    {"kind": "CONNECT", "a": "a", "b": "n.i"},
+                    {"kind": "CONNECT", "a": "n.o", "b": "r"},
+                    {"kind": "CONNECT", "a": "r", "b": "o"},
+                ],
+                "tests": [],
+            })
+        );
+        let files = json["files"].as_object().unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            ["STD.AND", "STD.NAND", "STD.NOT", "STD.XOR"]
+        );
+        assert_eq!(
+            files["STD.NOT"]["statements"][2],
+            json!({"kind": "CUSTOM", "code": "NOT"})
+        );
+        assert_eq!(json["search_path"], json!([]));
+    }
+
+    #[test]
+    fn expects_hold_the_values_of_the_test_section() {
+        let src = include_str!("test.chip");
+        let (chip, _) = parse_chip("latch", src);
+        assert_eq!(chip.tests.len(), 4);
+        assert_eq!(chip.tests[0].ins, [("q".to_string(), true)]);
+        let outs = |e: &Expect| {
+            e.outs
+                .iter()
+                .map(|(n, v, _)| (n.clone(), *v))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            outs(&chip.tests[0]),
+            [("o1".to_string(), false), ("o2".to_string(), true)]
+        );
+        assert!(chip.tests[1].ins.is_empty());
+        let (_, _, span) = &chip.tests[2].outs[1];
+        assert_eq!(&src[span.start..span.end], "o2=0");
+    }
+
+    #[test]
+    fn expects_only_go_in_the_test_section() {
+        let head = "IN a\nOUT b\nCONNECT a b\n";
+        assert_eq!(
+            errors(&format!("{}EXPECT a=1 => b=1\n", head)),
+            ["EXPECT can only go in the TEST section"]
+        );
+        assert_eq!(
+            errors(&format!("{}TEST\nEXPECT a=1 => b=1\nIN c\n", head)),
+            ["Only EXPECT can go in the TEST section"]
+        );
+        assert_eq!(
+            errors(&format!("{}TEST\nEXPECT b=1 => a=1 c=0\n", head)),
+            ["b is not an IN", "a is not an OUT", "Name c is not defined"]
+        );
+    }
+
+    #[test]
+    fn expects_give_each_pin_once() {
+        let src = "IN a\nOUT b\nCONNECT a b\nTEST\nEXPECT a=1 a=0 => b=1 b=1\n";
+        let errors = Chip::parse("test".into(), src, &mut Program::new()).unwrap_err();
+        let kinds = errors.iter().map(|e| (*e.kind).clone()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ErrorKind::AlreadyUsed("a".into()),
+                ErrorKind::AlreadyUsed("b".into())
+            ]
+        );
+        let second = errors[0].span.unwrap();
+        assert_eq!(second.start, src.find("a=0").unwrap());
+        assert_eq!(errors[0].labels[0].span.start, src.find("a=1").unwrap());
+    }
+}
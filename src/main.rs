@@ -1,18 +1,496 @@
-use pest::{iterators::Pair, Parser};
-use pest_derive::Parser;
+#![allow(clippy::upper_case_acronyms)]
+
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use pest::iterators::Pair;
 
 use recolored::*;
 
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
 mod ast;
 mod backend;
+mod error;
+mod expect;
 mod lexer;
-use backend::{Backend, JsBackend};
+mod sim;
+mod truth;
+mod vcd;
+mod yosys;
+use backend::netlist::{chip_ident, Probe};
+use backend::{
+    Backend, BlifBackend, CBackend, DotBackend, JsBackend, LogisimBackend, MinecraftBackend,
+    PythonBackend, RustBackend, SvgBackend, VerilogBackend, VhdlBackend, YosysBackend,
+};
+use error::CompileError;
 
 fn main() {
-    let src = include_str!("test.chip");
+    let matches = App::new("chip")
+        .version(crate_version!())
+        .about("Compiles CHIP logic descriptions")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Compiles a chip and everything it uses")
+                .arg(file_arg())
+                .arg(
+                    Arg::with_name("backend")
+                        .short("b")
+                        .long("backend")
+                        .takes_value(true)
+                        .possible_values(&["js", "verilog", "vhdl", "c", "rust", "python", "dot", "svg", "minecraft", "logisim", "blif", "yosys"])
+                        .default_value("js")
+                        .help("Backend used to generate the output"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Output file, stdout if not present. Extra files, like C headers, are written next to it"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Writes the parsed chips as JSON instead of compiling them"),
+                )
+                .arg(lib_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("table")
+                .about("Prints the truth table of a chip without feedback loops")
+                .arg(file_arg())
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "markdown", "csv"])
+                        .default_value("text")
+                        .help("How the table is written"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Output file, stdout if not present"),
+                )
+                .arg(lib_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("sim")
+                .about("Runs a chip, reading one line of inputs per run from stdin if none are given")
+                .arg(file_arg())
+                .arg(
+                    Arg::with_name("INPUTS")
+                        .multiple(true)
+                        .help("Value of every IN, in order, as 0 or 1"),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .short("d")
+                        .long("delay")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Runs with propagation delays, printing every change of the OUTs with its time. TICKS for every primitive, or NAME=TICKS for a CUSTOM (like NOT) or an instance path (like latch.not1)"),
+                )
+                .arg(
+                    Arg::with_name("vcd")
+                        .long("vcd")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Writes every signal of every run as a VCD waveform, a run is a tick without --delay"),
+                )
+                .arg(lib_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Runs the TEST sections of a chip and of every chip it uses")
+                .arg(file_arg())
+                .arg(lib_arg()),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("build", Some(m)) => build(m),
+        ("table", Some(m)) => table(m),
+        ("sim", Some(m)) => sim(m),
+        ("test", Some(m)) => test(m),
+        _ => unreachable!(),
+    }
+}
+
+fn file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("FILE").required(true).help(
+        "The .chip file to compile, or a Yosys JSON netlist, its stem is used as the chip name",
+    )
+}
+
+fn lib_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("lib")
+        .short("L")
+        .long("lib")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Adds a library root to search for USEd chips, before the ones in CHIP_PATH")
+}
+
+/// Parses the FILE argument into a chip, loading everything it uses into the program
+fn load(m: &ArgMatches) -> (lexer::Chip, lexer::Program) {
     let mut program = lexer::Program::new();
-    let chip = lexer::Chip::parse("main".into(), src, &mut program);
-    JsBackend::compile(chip, program);
+    for dir in m.values_of("lib").into_iter().flatten() {
+        program.add_library_root(dir);
+    }
+    program.add_env_library_roots();
+    let path = Path::new(m.value_of("FILE").unwrap());
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "main".into());
+    // The top chip is named after the file, which can't have a `.` like the USEd ones
+    if !lexer::is_chip_name(&name) || name.contains('.') {
+        or_exit(
+            Err(vec![CompileError::new(
+                error::ErrorKind::InvalidChipName(name.clone()),
+                None,
+                path.display().to_string(),
+            )]),
+            &program.sources,
+        )
+    }
+    // Netlists written by Yosys are imported as a chip
+    let chip = if path.extension() == Some("json".as_ref()) {
+        yosys::import(name, path, &mut program)
+    } else {
+        lexer::Chip::parse_file(name, path, &mut program)
+    };
+    let chip = or_exit(chip, &program.sources);
+    (chip, program)
+}
+
+fn build(m: &ArgMatches) {
+    let (chip, program) = load(m);
+    if m.is_present("json") {
+        let json = serde_json::to_string_pretty(&program.to_json(&chip)).unwrap() + "\n";
+        write_output(m.value_of("output"), json.as_bytes());
+        return;
+    }
+    let sources = program.sources.clone();
+    let (out, extra) = or_exit(
+        match m.value_of("backend").unwrap() {
+            "js" => compile::<JsBackend>(chip, program),
+            "verilog" => compile::<VerilogBackend>(chip, program),
+            "vhdl" => compile::<VhdlBackend>(chip, program),
+            "c" => compile::<CBackend>(chip, program),
+            "rust" => compile::<RustBackend>(chip, program),
+            "python" => compile::<PythonBackend>(chip, program),
+            "dot" => compile::<DotBackend>(chip, program),
+            "svg" => compile::<SvgBackend>(chip, program),
+            "minecraft" => compile::<MinecraftBackend>(chip, program),
+            "logisim" => compile::<LogisimBackend>(chip, program),
+            "blif" => compile::<BlifBackend>(chip, program),
+            "yosys" => compile::<YosysBackend>(chip, program),
+            _ => unreachable!(),
+        }
+        .map_err(|e| vec![e]),
+        &sources,
+    );
+    match m.value_of("output") {
+        Some(path) => {
+            write_output(Some(path), &out);
+            for (name, contents) in extra {
+                let path = Path::new(path).with_file_name(name);
+                write_output(path.to_str(), contents.as_bytes());
+            }
+        }
+        None => {
+            for (_, contents) in extra {
+                write_output(None, contents.as_bytes());
+            }
+            write_output(None, &out);
+        }
+    }
+}
+
+fn table(m: &ArgMatches) {
+    let (chip, program) = load(m);
+    let format = match m.value_of("format").unwrap() {
+        "text" => truth::Format::Text,
+        "markdown" => truth::Format::Markdown,
+        "csv" => truth::Format::Csv,
+        _ => unreachable!(),
+    };
+    let out = or_exit(
+        truth::table(&chip, &program, format).map_err(|e| vec![e]),
+        &program.sources,
+    );
+    write_output(m.value_of("output"), out.as_bytes());
+}
+
+fn sim(m: &ArgMatches) {
+    let (chip, program) = &load(m);
+    let show = |outs: &[bool]| {
+        chip.outs
+            .iter()
+            .zip(outs)
+            .map(|(o, v)| format!("{}={}", o, *v as u8))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    // Every run starts from the state the previous one left
+    let mut run: SimRun = match m.values_of("delay") {
+        None => {
+            let mut sim = or_exit(
+                sim::Sim::new(chip, program).map_err(|e| vec![e]),
+                &program.sources,
+            );
+            let probes = sim.flat().probes();
+            let mut vcd = vcd_file(m, chip, &probes);
+            let mut time = 0;
+            Box::new(move |ins| {
+                let outs = sim.run(ins).map_err(|e| e.render(&program.sources))?;
+                println!("{}", show(&outs));
+                if let Some(vcd) = &mut vcd {
+                    vcd.sample(time, &sim.values(&probes))
+                        .map_err(|e| format!("{} {}", "Error writing output:".red(), e))?;
+                }
+                time += 1;
+                Ok(())
+            })
+        }
+        Some(delays) => {
+            let delays = match parse_delays(delays.collect()) {
+                Ok(delays) => delays,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let mut sim = or_exit(
+                sim::EventSim::new(chip, program, &delays).map_err(|e| vec![e]),
+                &program.sources,
+            );
+            if let Some(name) = delays.by_name.keys().find(|n| {
+                !sim.flat()
+                    .primitives
+                    .iter()
+                    .any(|p| &&p.path == n || &&p.custom == n)
+            }) {
+                eprintln!(
+                    "{} there's no CUSTOM or instance path {}",
+                    "Invalid delay:".red(),
+                    name
+                );
+                std::process::exit(1);
+            }
+            let probes = sim.flat().probes();
+            let mut vcd = vcd_file(m, chip, &probes);
+            // Kept between runs so outputs that didn't change aren't printed again
+            let mut last = None;
+            Box::new(move |ins| {
+                let mut written = Ok(());
+                let res = sim.run(ins, |sim| {
+                    let outs = sim.outs();
+                    if last.as_ref() != Some(&outs) {
+                        println!("t={} {}", sim.time(), show(&outs));
+                        last = Some(outs);
+                    }
+                    if let (Some(vcd), Ok(())) = (&mut vcd, &written) {
+                        written = vcd.sample(sim.time(), &sim.values(&probes));
+                    }
+                });
+                written.map_err(|e| format!("{} {}", "Error writing output:".red(), e))?;
+                res.map_err(|e| e.render(&program.sources))
+            })
+        }
+    };
+    if let Some(values) = m.values_of("INPUTS") {
+        if let Err(e) = parse_inputs(chip, &values.collect::<Vec<_>>()).and_then(|ins| run(&ins)) {
+            eprintln!("{}", e.trim_end());
+            std::process::exit(1);
+        }
+        return;
+    }
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    if interactive {
+        eprintln!("IN {}, Ctrl-D to exit", chip.ins.join(" "));
+    }
+    loop {
+        if interactive {
+            print!("> ");
+            io::stdout().flush().unwrap();
+        }
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let ins = match parse_inputs(chip, &line.split_whitespace().collect::<Vec<_>>()) {
+            Ok(ins) => ins,
+            // A typo at the prompt can be fixed on the next line
+            Err(e) if interactive => {
+                eprintln!("{}", e);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        // The state after a failed run isn't one the chip can be in, so nothing runs after it
+        if let Err(e) = run(&ins) {
+            eprintln!("{}", e.trim_end());
+            std::process::exit(1);
+        }
+    }
+}
+
+fn test(m: &ArgMatches) {
+    let (chip, program) = load(m);
+    let mut tested = false;
+    let mut failed = 0;
+    // The chips it uses first, their failures probably explain the ones of the chips using them
+    for c in backend::netlist::dependencies(&chip, &program)
+        .into_iter()
+        .chain(std::iter::once(&chip))
+    {
+        if c.tests.is_empty() {
+            continue;
+        }
+        tested = true;
+        let (passed, failures) = or_exit(
+            expect::run(c, &program).map_err(|e| vec![e]),
+            &program.sources,
+        );
+        for e in &failures {
+            eprintln!("{}", e.render(&program.sources));
+        }
+        println!(
+            "{}: {} passed, {} failed",
+            c.name,
+            passed,
+            c.tests.len() - passed
+        );
+        failed += c.tests.len() - passed;
+    }
+    if !tested {
+        println!("{} and the chips it uses have no TEST section", chip.name);
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// The file given with `--vcd`, with the header for `probes` written
+fn vcd_file(m: &ArgMatches, chip: &lexer::Chip, probes: &[Probe]) -> Option<vcd::Vcd<fs::File>> {
+    let path = m.value_of("vcd")?;
+    let vcd =
+        fs::File::create(path).and_then(|f| vcd::Vcd::new(f, &chip_ident(&chip.name), probes));
+    match vcd {
+        Ok(vcd) => Some(vcd),
+        Err(e) => {
+            eprintln!("{} {}", "Error writing output:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_inputs(chip: &lexer::Chip, values: &[&str]) -> Result<Vec<bool>, String> {
+    if values.len() != chip.ins.len() {
+        return Err(format!(
+            "{} expected {} inputs ({}), got {}",
+            "Invalid inputs:".red(),
+            chip.ins.len(),
+            chip.ins.join(" "),
+            values.len()
+        ));
+    }
+    values
+        .iter()
+        .map(|v| match *v {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(format!("{} {} isn't 0 or 1", "Invalid inputs:".red(), v)),
+        })
+        .collect()
+}
+
+/// One run of `sim` with the given inputs, printing the OUTs or returning the error to print
+type SimRun<'a> = Box<dyn FnMut(&[bool]) -> Result<(), String> + 'a>;
+
+/// `TICKS` or `NAME=TICKS` for every `--delay`
+fn parse_delays(values: Vec<&str>) -> Result<sim::Delays, String> {
+    let mut delays = sim::Delays::default();
+    for value in values {
+        let (name, ticks) = match value.split_once('=') {
+            Some((name, ticks)) => (Some(name), ticks),
+            None => (None, value),
+        };
+        let ticks = match ticks.parse::<u64>() {
+            Ok(t) if t > 0 => t,
+            _ => {
+                return Err(format!(
+                    "{} {} isn't a number of ticks above 0",
+                    "Invalid delay:".red(),
+                    ticks
+                ))
+            }
+        };
+        match name {
+            Some(name) => {
+                delays.by_name.insert(name.to_string(), ticks);
+            }
+            None => delays.default = ticks,
+        }
+    }
+    Ok(delays)
+}
+
+/// Main output of the backend and the extra files that go next to it
+type Compiled = (Vec<u8>, Vec<(String, String)>);
+
+fn compile<B: Backend>(
+    chip: lexer::Chip,
+    program: lexer::Program,
+) -> Result<Compiled, CompileError> {
+    let extra = B::extra_files(&chip, &program)?;
+    Ok((B::compile(chip, program)?.as_ref().to_vec(), extra))
+}
+
+fn or_exit<T>(res: Result<T, Vec<CompileError>>, sources: &HashMap<String, String>) -> T {
+    match res {
+        Ok(v) => v,
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("{}", e.render(sources));
+            }
+            if errors.len() > 1 {
+                eprintln!(
+                    "{}{} aborting due to {} previous errors",
+                    "error".red().bold(),
+                    ":".bold(),
+                    errors.len()
+                );
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn write_output(path: Option<&str>, contents: &[u8]) {
+    let res = match path {
+        Some(path) => fs::write(path, contents),
+        None => io::stdout().write_all(contents),
+    };
+    if let Err(e) = res {
+        eprintln!("{} {}", "Error writing output:".red(), e);
+        std::process::exit(1);
+    }
 }
 
 #[allow(dead_code)]
@@ -24,10 +502,10 @@ fn print(p: Pair<lexer::Rule>, s: String, e: String) {
     );
     let inner = p.into_inner().collect::<Vec<_>>();
     if inner.len() > 1 {
-        println!("");
+        println!();
         let mut i = 0;
         for pair in inner.clone() {
-            println!("{} ┃ ", s[..s.len() - " ┣ ".len()].to_string());
+            println!("{} ┃ ", &s[..s.len() - " ┣ ".len()]);
             if i < inner.len() - 1 {
                 print!("{}", s);
             } else {
@@ -37,14 +515,11 @@ fn print(p: Pair<lexer::Rule>, s: String, e: String) {
             print(pair, format!(" ┃ {}", s), format!(" ┃ {}", e));
             i += 1;
             if i < inner.len() {
-                println!("");
+                println!();
             }
         }
-    } else if inner.len() > 0 {
+    } else if let Some(pair) = inner.into_iter().next() {
         print!("{}", " > ".red());
-        for pair in inner {
-            print(pair, s.clone(), e);
-            break;
-        }
+        print(pair, s, e);
     }
 }
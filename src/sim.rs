@@ -0,0 +1,442 @@
+use crate::backend::netlist::{self, Flat, Primitive, Probe, Source};
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Runs a chip directly, flattened down to its CUSTOM primitives. The primitives keep their
+/// outputs between runs, so chips with feedback loops, like latches, remember their state
+#[derive(Debug, Clone)]
+pub struct Sim<'a> {
+    flat: Flat<'a>,
+    /// INs of the last run
+    ins: Vec<bool>,
+    /// Outputs of every primitive
+    state: Vec<Vec<bool>>,
+}
+
+impl<'a> Sim<'a> {
+    pub fn new(chip: &'a Chip, program: &'a Program) -> Result<Self, CompileError> {
+        let flat = Flat::new(chip, program);
+        let mut state = Vec::new();
+        for primitive in &flat.primitives {
+            let ins = vec![false; primitive.chip.ins.len()];
+            if run_custom(&primitive.custom, &ins).is_none() {
+                return Err(netlist::custom_error(
+                    primitive.chip,
+                    &primitive.custom,
+                    "the simulator",
+                ));
+            }
+            state.push(vec![false; primitive.chip.outs.len()]);
+        }
+        Ok(Self {
+            ins: vec![false; chip.ins.len()],
+            flat,
+            state,
+        })
+    }
+
+    pub fn flat(&self) -> &Flat<'a> {
+        &self.flat
+    }
+
+    /// Values of `probes` after the last run
+    pub fn values(&self, probes: &[Probe]) -> Vec<bool> {
+        probes
+            .iter()
+            .map(|p| value(&p.sources, &self.ins, &self.state))
+            .collect()
+    }
+
+    /// Updates the primitives until none of them changes and returns the OUTs,
+    /// RAILs are wired-OR like in the backends
+    pub fn run(&mut self, ins: &[bool]) -> Result<Vec<bool>, CompileError> {
+        self.ins = ins.to_vec();
+        // Every pass only depends on the state before it, so once a state comes back
+        // the passes repeat forever without settling
+        let mut seen = HashSet::new();
+        while seen.insert(self.state.clone()) {
+            let mut changed = false;
+            for (p, primitive) in self.flat.primitives.iter().enumerate() {
+                let args = primitive
+                    .ins
+                    .iter()
+                    .map(|sources| value(sources, ins, &self.state))
+                    .collect::<Vec<_>>();
+                let outs = run_custom(&primitive.custom, &args).unwrap();
+                if outs != self.state[p] {
+                    self.state[p] = outs;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return Ok(self
+                    .flat
+                    .outs
+                    .iter()
+                    .map(|sources| value(sources, ins, &self.state))
+                    .collect());
+            }
+        }
+        Err(CompileError::new(
+            ErrorKind::Oscillation(self.flat.chip.name.clone()),
+            None,
+            self.flat.chip.file_name(),
+        ))
+    }
+}
+
+/// Propagation delay of the primitives in ticks, by instance path (like `latch.not1`)
+/// or by CUSTOM (like `NOT`), the ones matching neither take `default`. Delays are at least 1,
+/// a loop without delay would change forever without time moving
+#[derive(Debug, Clone)]
+pub struct Delays {
+    pub default: u64,
+    pub by_name: HashMap<String, u64>,
+}
+
+impl Default for Delays {
+    fn default() -> Self {
+        Self {
+            default: 1,
+            by_name: HashMap::new(),
+        }
+    }
+}
+
+impl Delays {
+    fn of(&self, primitive: &Primitive) -> u64 {
+        self.by_name
+            .get(&primitive.path)
+            .or_else(|| self.by_name.get(&primitive.custom))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// A change of the outputs of a primitive as `(time, order, primitive, outputs)`, the order
+/// keeps the ones happening at the same time in the order they were scheduled
+type Event = (u64, u64, usize, Vec<bool>);
+
+/// The outputs of every primitive and the changes they have scheduled, as
+/// `(ticks from now, primitive, outputs)`
+type Snapshot = (Vec<Vec<bool>>, Vec<(u64, usize, Vec<bool>)>);
+
+/// Runs a chip with a delay for every primitive. When the inputs of a primitive change its new
+/// outputs are scheduled `delay` ticks later, replacing the change it had scheduled, so pulses
+/// shorter than the delay are swallowed like in real gates, but glitches and races between
+/// the primitives show up in the OUTs
+#[derive(Debug, Clone)]
+pub struct EventSim<'a> {
+    flat: Flat<'a>,
+    delays: Vec<u64>,
+    ins: Vec<bool>,
+    /// Outputs of every primitive
+    state: Vec<Vec<bool>>,
+    /// Order of the change every primitive has scheduled, the ones it replaced are skipped
+    pending: Vec<Option<u64>>,
+    time: u64,
+    queue: BinaryHeap<Reverse<Event>>,
+    scheduled: u64,
+}
+
+impl<'a> EventSim<'a> {
+    /// Everything starts at 0, with the changes that causes scheduled
+    pub fn new(
+        chip: &'a Chip,
+        program: &'a Program,
+        delays: &Delays,
+    ) -> Result<Self, CompileError> {
+        let flat = Flat::new(chip, program);
+        for primitive in &flat.primitives {
+            let ins = vec![false; primitive.chip.ins.len()];
+            if run_custom(&primitive.custom, &ins).is_none() {
+                return Err(netlist::custom_error(
+                    primitive.chip,
+                    &primitive.custom,
+                    "the simulator",
+                ));
+            }
+        }
+        let state = flat
+            .primitives
+            .iter()
+            .map(|p| vec![false; p.chip.outs.len()])
+            .collect::<Vec<_>>();
+        let mut sim = Self {
+            delays: flat.primitives.iter().map(|p| delays.of(p)).collect(),
+            ins: vec![false; chip.ins.len()],
+            pending: vec![None; state.len()],
+            state,
+            time: 0,
+            queue: BinaryHeap::new(),
+            scheduled: 0,
+            flat,
+        };
+        for p in 0..sim.flat.primitives.len() {
+            sim.update(p);
+        }
+        Ok(sim)
+    }
+
+    pub fn flat(&self) -> &Flat<'a> {
+        &self.flat
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Values of `probes` at the current time
+    pub fn values(&self, probes: &[Probe]) -> Vec<bool> {
+        probes
+            .iter()
+            .map(|p| value(&p.sources, &self.ins, &self.state))
+            .collect()
+    }
+
+    pub fn outs(&self) -> Vec<bool> {
+        self.flat
+            .outs
+            .iter()
+            .map(|sources| value(sources, &self.ins, &self.state))
+            .collect()
+    }
+
+    /// Changes the INs at the current time
+    pub fn set(&mut self, ins: &[bool]) {
+        let changed = (0..ins.len())
+            .filter(|&i| ins[i] != self.ins[i])
+            .map(Source::In)
+            .collect::<Vec<_>>();
+        self.ins = ins.to_vec();
+        self.propagate(&changed);
+    }
+
+    /// Moves to the next time something changes and applies every change happening then,
+    /// returning the new time or `None` if nothing is scheduled
+    pub fn step(&mut self) -> Option<u64> {
+        // Changes that were replaced don't move the time
+        while let Some(Reverse((_, order, p, _))) = self.queue.peek() {
+            if self.pending[*p] == Some(*order) {
+                break;
+            }
+            self.queue.pop();
+        }
+        let Reverse((time, ..)) = self.queue.peek()?;
+        self.time = *time;
+        let before = self.state.clone();
+        while let Some(Reverse((time, order, p, outs))) = self.queue.peek().cloned() {
+            if time != self.time {
+                break;
+            }
+            self.queue.pop();
+            if self.pending[p] == Some(order) {
+                self.pending[p] = None;
+                self.state[p] = outs;
+            }
+        }
+        let mut changed = Vec::new();
+        for (p, (old, new)) in before.iter().zip(&self.state).enumerate() {
+            for o in 0..old.len() {
+                if old[o] != new[o] {
+                    changed.push(Source::Primitive(p, o));
+                }
+            }
+        }
+        self.propagate(&changed);
+        Some(self.time)
+    }
+
+    /// Sets the INs and runs until nothing is scheduled, calling `observe` right after setting them
+    /// and after every time something changes. A chip coming back to the outputs and scheduled
+    /// changes it had at some earlier time is oscillating. The next run starts a tick after this
+    /// one settles, so its last values last at least that long
+    pub fn run(
+        &mut self,
+        ins: &[bool],
+        mut observe: impl FnMut(&Self),
+    ) -> Result<(), CompileError> {
+        self.set(ins);
+        observe(self);
+        let mut seen = HashSet::new();
+        while self.step().is_some() {
+            if !seen.insert(self.snapshot()) {
+                return Err(CompileError::new(
+                    ErrorKind::Oscillation(self.flat.chip.name.clone()),
+                    None,
+                    self.flat.chip.file_name(),
+                ));
+            }
+            observe(self);
+        }
+        self.time += 1;
+        Ok(())
+    }
+
+    /// Everything the next steps depend on
+    fn snapshot(&self) -> Snapshot {
+        let mut scheduled = self
+            .queue
+            .iter()
+            .filter(|Reverse((_, order, p, _))| self.pending[*p] == Some(*order))
+            .map(|Reverse((time, _, p, outs))| (time - self.time, *p, outs.clone()))
+            .collect::<Vec<_>>();
+        scheduled.sort();
+        (self.state.clone(), scheduled)
+    }
+
+    /// Schedules the new outputs of the primitives reading from `changed`
+    fn propagate(&mut self, changed: &[Source]) {
+        for p in 0..self.flat.primitives.len() {
+            if self.flat.primitives[p]
+                .ins
+                .iter()
+                .flatten()
+                .any(|s| changed.contains(s))
+            {
+                self.update(p);
+            }
+        }
+    }
+
+    /// Schedules the outputs of `p` for its current inputs, if they're the ones it has
+    /// already the change it had scheduled is dropped
+    fn update(&mut self, p: usize) {
+        let args = self.flat.primitives[p]
+            .ins
+            .iter()
+            .map(|sources| value(sources, &self.ins, &self.state))
+            .collect::<Vec<_>>();
+        let outs = run_custom(&self.flat.primitives[p].custom, &args).unwrap();
+        if outs == self.state[p] {
+            self.pending[p] = None;
+            return;
+        }
+        self.pending[p] = Some(self.scheduled);
+        self.queue.push(Reverse((
+            self.time + self.delays[p],
+            self.scheduled,
+            p,
+            outs,
+        )));
+        self.scheduled += 1;
+    }
+}
+
+/// The OR of everything driving a signal
+fn value(sources: &[Source], ins: &[bool], state: &[Vec<bool>]) -> bool {
+    sources.iter().any(|s| match *s {
+        Source::In(i) => ins[i],
+        Source::Primitive(p, o) => state[p][o],
+    })
+}
+
+fn run_custom(n: &str, ins: &[bool]) -> Option<Vec<bool>> {
+    match n {
+        "NOT" => Some(vec![!ins[0]]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    const OSCILLATOR: &str =
+        "USE STD.NOT AS not\nOUT o\nCHIP not n\nCONNECT n.o n.i\nCONNECT n.o o\n";
+    const CHAIN: &str = "USE STD.NOT AS not\nIN i\nOUT o\nCHIP not not1\nCHIP not not2\n\
+                         CONNECT i not1.i\nCONNECT not1.o not2.i\nCONNECT not2.o o\n";
+
+    /// Every change of the OUTs with its time
+    fn changes(sim: &mut EventSim, ins: &[bool]) -> Result<Vec<(u64, Vec<bool>)>, CompileError> {
+        let mut changes: Vec<(u64, Vec<bool>)> = Vec::new();
+        sim.run(ins, |sim| {
+            let outs = sim.outs();
+            if changes.last().is_none_or(|(_, last)| *last != outs) {
+                changes.push((sim.time(), outs));
+            }
+        })?;
+        Ok(changes)
+    }
+
+    #[test]
+    fn latches_keep_their_state_between_runs() {
+        let (chip, program) = parse_chip("test", include_str!("test.chip"));
+        let mut sim = Sim::new(&chip, &program).unwrap();
+        assert_eq!(sim.run(&[true, false]).unwrap(), [false, true]);
+        assert_eq!(sim.run(&[false, false]).unwrap(), [false, true]);
+        assert_eq!(sim.run(&[false, true]).unwrap(), [true, false]);
+        assert_eq!(sim.run(&[false, false]).unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn oscillations_are_errors() {
+        let (chip, program) = parse_chip("test", OSCILLATOR);
+        let mut sim = Sim::new(&chip, &program).unwrap();
+        assert_eq!(
+            *sim.run(&[]).unwrap_err().kind,
+            ErrorKind::Oscillation("test".into())
+        );
+    }
+
+    #[test]
+    fn event_oscillations_are_errors() {
+        let (chip, program) = parse_chip("test", OSCILLATOR);
+        let mut sim = EventSim::new(&chip, &program, &Delays::default()).unwrap();
+        assert_eq!(
+            *sim.run(&[], |_| {}).unwrap_err().kind,
+            ErrorKind::Oscillation("test".into())
+        );
+    }
+
+    #[test]
+    fn changes_take_the_delay_of_every_primitive() {
+        let (chip, program) = parse_chip("test", CHAIN);
+        let mut sim = EventSim::new(&chip, &program, &Delays::default()).unwrap();
+        // Both NOTs power up to 1 at once, then the second one sees the first
+        assert_eq!(
+            changes(&mut sim, &[false]).unwrap(),
+            [(0, vec![false]), (1, vec![true]), (2, vec![false])]
+        );
+        assert_eq!(sim.time(), 3);
+        assert_eq!(
+            changes(&mut sim, &[true]).unwrap(),
+            [(3, vec![false]), (5, vec![true])]
+        );
+    }
+
+    #[test]
+    fn pulses_shorter_than_the_delay_are_swallowed() {
+        let (chip, program) = parse_chip("test", CHAIN);
+        let delays = Delays {
+            default: 1,
+            by_name: std::iter::once(("not2".to_string(), 3)).collect(),
+        };
+        let mut sim = EventSim::new(&chip, &program, &delays).unwrap();
+        assert_eq!(changes(&mut sim, &[false]).unwrap(), [(0, vec![false])]);
+        assert_eq!(
+            changes(&mut sim, &[true]).unwrap(),
+            [(2, vec![false]), (6, vec![true])]
+        );
+    }
+
+    #[test]
+    fn slow_latches_settle() {
+        let (chip, program) = parse_chip("test", include_str!("test.chip"));
+        let delays = Delays {
+            default: 1,
+            by_name: std::iter::once(("not2".to_string(), 2)).collect(),
+        };
+        let mut sim = EventSim::new(&chip, &program, &delays).unwrap();
+        sim.run(&[false, false], |_| {}).unwrap();
+        assert_eq!(sim.outs(), [true, false]);
+        sim.run(&[false, true], |_| {}).unwrap();
+        assert_eq!(sim.outs(), [true, false]);
+        sim.run(&[true, false], |_| {}).unwrap();
+        assert_eq!(sim.outs(), [false, true]);
+    }
+}
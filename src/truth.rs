@@ -0,0 +1,153 @@
+use std::fmt::Write;
+
+use crate::backend::netlist::{self, Netlist};
+use crate::error::{CompileError, ErrorKind};
+use crate::lexer::{Chip, Program};
+use crate::sim::Sim;
+
+/// How `table` writes the rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Columns aligned with spaces, the INs and OUTs split by a `|`
+    Text,
+    Markdown,
+    Csv,
+}
+
+/// Most INs `table` takes, the table doubles with each one
+pub const MAX_INPUTS: usize = 20;
+
+/// The outputs of `chip` for every combination of its inputs, counting up in binary with
+/// the first IN as the most significant bit
+pub fn table(chip: &Chip, program: &Program, format: Format) -> Result<String, CompileError> {
+    if chip.ins.len() > MAX_INPUTS {
+        return Err(CompileError::new(
+            ErrorKind::TooManyInputs(chip.name.clone(), chip.ins.len()),
+            None,
+            chip.file_name(),
+        ));
+    }
+    // Checked up front so chips with loops fail even if they have no inputs
+    for c in netlist::dependencies(chip, program)
+        .into_iter()
+        .chain(std::iter::once(chip))
+    {
+        let netlist = Netlist::new(c, program);
+        if netlist.eval_order().is_none() {
+            return Err(
+                netlist.loop_error(|path| ErrorKind::NotCombinational(c.name.clone(), path))
+            );
+        }
+    }
+    let mut sim = Sim::new(chip, program)?;
+    let mut rows = Vec::new();
+    for n in 0..1u64 << chip.ins.len() {
+        let ins = (0..chip.ins.len())
+            .rev()
+            .map(|bit| n >> bit & 1 == 1)
+            .collect::<Vec<_>>();
+        let outs = sim.run(&ins)?;
+        rows.push((ins, outs));
+    }
+
+    let bit = |b: &bool| if *b { "1" } else { "0" };
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            let cells = |names: &[String], values: &[bool]| {
+                names
+                    .iter()
+                    .zip(values)
+                    .map(|(n, v)| format!("{:>w$}", bit(v), w = n.len()))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            writeln!(out, "{} | {}", chip.ins.join(" "), chip.outs.join(" ")).unwrap();
+            for (ins, outs) in &rows {
+                writeln!(
+                    out,
+                    "{} | {}",
+                    cells(&chip.ins, ins),
+                    cells(&chip.outs, outs)
+                )
+                .unwrap();
+            }
+        }
+        Format::Markdown => {
+            let names = chip.ins.iter().chain(&chip.outs).collect::<Vec<_>>();
+            let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+            writeln!(
+                out,
+                "{}",
+                line(names.iter().map(|n| n.to_string()).collect())
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}",
+                line(names.iter().map(|n| "-".repeat(n.len().max(3))).collect())
+            )
+            .unwrap();
+            for (ins, outs) in &rows {
+                let cells = ins.iter().chain(outs).map(|v| bit(v).to_string()).collect();
+                writeln!(out, "{}", line(cells)).unwrap();
+            }
+        }
+        Format::Csv => {
+            let names = chip
+                .ins
+                .iter()
+                .chain(&chip.outs)
+                .cloned()
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", names.join(",")).unwrap();
+            for (ins, outs) in &rows {
+                let cells = ins.iter().chain(outs).map(bit).collect::<Vec<_>>();
+                writeln!(out, "{}", cells.join(",")).unwrap();
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn std_and_in_every_format() {
+        let (_, program) = parse_chip("test", "");
+        let and = program.get_chip(&"STD.AND".to_string());
+        assert_eq!(
+            table(and, &program, Format::Text).unwrap(),
+            "in0 in1 | out\n  0   0 |   0\n  0   1 |   0\n  1   0 |   0\n  1   1 |   1\n"
+        );
+        assert_eq!(
+            table(and, &program, Format::Markdown).unwrap(),
+            "| in0 | in1 | out |\n| --- | --- | --- |\n| 0 | 0 | 0 |\n| 0 | 1 | 0 |\n\
+             | 1 | 0 | 0 |\n| 1 | 1 | 1 |\n"
+        );
+        assert_eq!(
+            table(and, &program, Format::Csv).unwrap(),
+            "in0,in1,out\n0,0,0\n0,1,0\n1,0,0\n1,1,1\n"
+        );
+    }
+
+    #[test]
+    fn too_many_inputs_or_loops_have_no_table() {
+        let ins = (0..=MAX_INPUTS)
+            .map(|i| format!("IN i{}\n", i))
+            .collect::<String>();
+        let (chip, program) = parse_chip("wide", &ins);
+        let error = table(&chip, &program, Format::Csv).unwrap_err();
+        assert_eq!(
+            *error.kind,
+            ErrorKind::TooManyInputs("wide".into(), MAX_INPUTS + 1)
+        );
+
+        let (chip, program) = parse_chip("test", include_str!("test.chip"));
+        let error = table(&chip, &program, Format::Csv).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::NotCombinational(..)));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{self, Write};
+
+use crate::backend::netlist::Probe;
+
+/// Writes the values of some probes over time as a Value Change Dump, for waveform viewers
+/// like GTKWave. Every instance gets a scope inside the one of the chip containing it
+pub struct Vcd<W: Write> {
+    out: W,
+    /// Values written last, `None` before the first sample
+    last: Option<Vec<bool>>,
+    time: Option<u64>,
+}
+
+impl<W: Write> Vcd<W> {
+    /// Writes the header, with the top chip as the outermost scope and a tick as 1 ns
+    pub fn new(mut out: W, top: &str, probes: &[Probe]) -> io::Result<Self> {
+        writeln!(out, "$version chip {} $end", env!("CARGO_PKG_VERSION"))?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module {} $end", top)?;
+        let mut scope: &[String] = &[];
+        for (i, probe) in probes.iter().enumerate() {
+            let common = scope
+                .iter()
+                .zip(&probe.scope)
+                .take_while(|(a, b)| a == b)
+                .count();
+            for _ in common..scope.len() {
+                writeln!(out, "$upscope $end")?;
+            }
+            for name in &probe.scope[common..] {
+                writeln!(out, "$scope module {} $end", name)?;
+            }
+            scope = &probe.scope;
+            writeln!(out, "$var wire 1 {} {} $end", code(i), probe.name)?;
+        }
+        for _ in 0..=scope.len() {
+            writeln!(out, "$upscope $end")?;
+        }
+        writeln!(out, "$enddefinitions $end")?;
+        Ok(Self {
+            out,
+            last: None,
+            time: None,
+        })
+    }
+
+    /// Writes the values that changed since the last sample, `values` are in the order of the probes
+    pub fn sample(&mut self, time: u64, values: &[bool]) -> io::Result<()> {
+        let changed = (0..values.len())
+            .filter(|&i| self.last.as_ref().is_none_or(|last| last[i] != values[i]))
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        if self.time != Some(time) {
+            writeln!(self.out, "#{}", time)?;
+            self.time = Some(time);
+        }
+        let first = self.last.is_none();
+        if first {
+            writeln!(self.out, "$dumpvars")?;
+        }
+        for i in changed {
+            writeln!(self.out, "{}{}", values[i] as u8, code(i))?;
+        }
+        if first {
+            writeln!(self.out, "$end")?;
+        }
+        self.last = Some(values.to_vec());
+        self.out.flush()
+    }
+}
+
+/// Identifier of the nth signal, in base 94 with the printable ASCII characters
+fn code(mut n: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return code;
+        }
+        n -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::netlist::Flat;
+    use crate::lexer::parse_chip;
+
+    #[test]
+    fn instances_are_scopes_inside_their_chip() {
+        let (chip, program) = parse_chip(
+            "test",
+            "USE STD.AND AS and\nIN a\nIN b\nOUT o\nCHIP and x\n\
+             CONNECT a x.in0\nCONNECT b x.in1\nCONNECT x.out o\n",
+        );
+        let probes = Flat::new(&chip, &program).probes();
+        let mut vcd = Vcd::new(Vec::new(), "test", &probes).unwrap();
+        let mut values = vec![false; probes.len()];
+        vcd.sample(0, &values).unwrap();
+        vcd.sample(1, &values).unwrap();
+        values[0] = true;
+        vcd.sample(2, &values).unwrap();
+        let header = "$timescale 1ns $end\n\
+                      $scope module test $end\n\
+                      $var wire 1 ! a $end\n$var wire 1 \" b $end\n$var wire 1 # o $end\n\
+                      $scope module x $end\n\
+                      $var wire 1 $ in0 $end\n$var wire 1 % in1 $end\n$var wire 1 & out $end\n\
+                      $scope module nt1 $end\n$var wire 1 ' i $end\n$var wire 1 ( o $end\n\
+                      $upscope $end\n\
+                      $scope module nt2 $end\n$var wire 1 ) i $end\n$var wire 1 * o $end\n\
+                      $upscope $end\n\
+                      $scope module nt3 $end\n$var wire 1 + i $end\n$var wire 1 , o $end\n\
+                      $upscope $end\n$upscope $end\n$upscope $end\n$enddefinitions $end\n";
+        // Only the first sample dumps every value, and the unchanged one writes nothing
+        let dump = "#0\n$dumpvars\n0!\n0\"\n0#\n0$\n0%\n0&\n0'\n0(\n0)\n0*\n0+\n0,\n$end\n\
+                    #2\n1!\n";
+        let version = format!("$version chip {} $end\n", env!("CARGO_PKG_VERSION"));
+        assert_eq!(String::from_utf8(vcd.out).unwrap(), version + header + dump);
+    }
+
+    #[test]
+    fn codes_are_printable_ascii() {
+        assert_eq!(code(0), "!");
+        assert_eq!(code(93), "~");
+        assert_eq!(code(94), "!!");
+        assert_eq!(code(95), "\"!");
+    }
+}
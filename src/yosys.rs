@@ -0,0 +1,721 @@
+use serde_json::{Map, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::ast::AST;
+use crate::error::{CompileError, ErrorKind, Span};
+use crate::lexer::{Chip, Program};
+
+/// Imports a Yosys JSON netlist (from `write_json`) as a chip named `name`, made from its top module.
+/// The modules it instantiates are added to the program, the gate cells become STD chips and
+/// the nets driven by OR cells become RAILs, as they're wired-OR
+pub fn import(name: String, path: &Path, program: &mut Program) -> Result<Chip, Vec<CompileError>> {
+    let file = path.display().to_string();
+    let src = fs::read_to_string(path).map_err(|e| {
+        vec![CompileError::new(
+            ErrorKind::Io(e.to_string()),
+            None,
+            file.clone(),
+        )]
+    })?;
+    program.sources.insert(file.clone(), src.clone());
+    let json = serde_json::from_str::<Value>(&src).map_err(|e| {
+        let offset = json_offset(&src, e.line(), e.column());
+        let span = Span {
+            start: offset,
+            end: offset,
+        };
+        vec![CompileError::new(
+            ErrorKind::Parse(e.to_string()),
+            Some(span),
+            file.clone(),
+        )]
+    })?;
+    let invalid = |message: &str| {
+        vec![CompileError::new(
+            ErrorKind::Parse(message.into()),
+            None,
+            file.clone(),
+        )]
+    };
+    let modules = json
+        .get("modules")
+        .and_then(Value::as_object)
+        .ok_or_else(|| invalid("expected a modules object"))?;
+    let top = modules
+        .iter()
+        .find(|(_, m)| is_top(m))
+        .or_else(|| modules.iter().find(|(n, _)| **n == name))
+        .or_else(|| modules.iter().next_back())
+        .map(|(n, _)| n.clone())
+        .ok_or_else(|| invalid("there are no modules"))?;
+
+    let mut import = Import {
+        path,
+        file: file.clone(),
+        src: &src,
+        modules,
+        imported: HashMap::new(),
+        loading: Vec::new(),
+        chips: Names::default(),
+    };
+    for chip in program.files.keys() {
+        import.chips.0.insert(chip.clone());
+    }
+    import.chips.0.insert(name.clone());
+    let chip = import.module(&top, name, program).map_err(|e| vec![e])?;
+    Ok(chip)
+}
+
+/// The `top` attribute, a binary string like every other attribute in `write_json`
+fn is_top(module: &Value) -> bool {
+    match module.pointer("/attributes/top") {
+        Some(Value::String(s)) => s.ends_with('1'),
+        Some(Value::Number(n)) => n.as_u64() == Some(1),
+        _ => false,
+    }
+}
+
+/// A signal in a module, the constants `x` and `z` are taken as 0 like unconnected readers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Bit {
+    Net(u64),
+    Zero,
+    One,
+}
+
+impl Bit {
+    fn from_json(v: &Value) -> Option<Self> {
+        match v {
+            Value::Number(n) => n.as_u64().map(Self::Net),
+            Value::String(s) => match s.as_str() {
+                "1" => Some(Self::One),
+                "0" | "x" | "z" => Some(Self::Zero),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// What gives a net its value
+#[derive(Debug, Clone)]
+enum Driver {
+    /// An IN or the output of a sub-chip
+    Pin(String),
+    /// An OR cell (or a buffer) of other nets
+    Or(Vec<Bit>),
+}
+
+/// Names that can be written in a .chip file, alphanumeric, starting with a letter and not
+/// repeated
+#[derive(Debug, Clone, Default)]
+struct Names(HashSet<String>);
+
+impl Names {
+    fn add(&mut self, name: &str) -> String {
+        let base = name
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect::<String>();
+        let base = match base.chars().next() {
+            Some(c) if c.is_ascii_alphabetic() => base,
+            _ => format!("n{}", base),
+        };
+        let mut unique = base.clone();
+        let mut i = 1;
+        while !self.0.insert(unique.clone()) {
+            unique = format!("{}{}", base, i);
+            i += 1;
+        }
+        unique
+    }
+}
+
+/// Chip name of an imported module and the pin of every bit of its ports
+#[derive(Debug, Clone)]
+struct Imported {
+    chip: String,
+    pins: HashMap<(String, usize), String>,
+}
+
+struct Import<'a> {
+    path: &'a Path,
+    file: String,
+    src: &'a str,
+    modules: &'a Map<String, Value>,
+    imported: HashMap<String, Imported>,
+    loading: Vec<String>,
+    chips: Names,
+}
+
+impl<'a> Import<'a> {
+    fn error(&self, kind: ErrorKind, span: Option<Span>) -> CompileError {
+        CompileError::new(kind, span, self.file.clone())
+    }
+
+    /// Span of the first `"name"` in the file, to point errors at a module or a cell
+    fn find(&self, name: &str) -> Option<Span> {
+        let quoted = format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        self.src.find(&quoted).map(|start| Span {
+            start,
+            end: start + quoted.len(),
+        })
+    }
+
+    fn invalid(&self, what: &str, name: &str, message: &str) -> CompileError {
+        self.error(
+            ErrorKind::Parse(format!("{} {} {}", what, name, message)),
+            self.find(name),
+        )
+    }
+
+    fn module(
+        &mut self,
+        key: &str,
+        chip_name: String,
+        program: &mut Program,
+    ) -> Result<Chip, CompileError> {
+        let modules = self.modules;
+        let json = &modules[key];
+        let mut module = Module {
+            names: Names::default(),
+            uses: HashMap::new(),
+            ast: Vec::new(),
+            chips: Vec::new(),
+            drivers: HashMap::new(),
+            readers: HashMap::new(),
+            next: 0,
+            one: None,
+        };
+        let mut chip = Chip::new(chip_name);
+        chip.path = Some(self.path.to_path_buf());
+        let mut pins = HashMap::new();
+
+        let empty = Map::new();
+        let ports = json
+            .get("ports")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        for (port, value) in ports {
+            let bits = self.bits(value.get("bits"), "Port", port)?;
+            for (k, bit) in bits.iter().enumerate() {
+                let pin = if bits.len() == 1 {
+                    module.names.add(port)
+                } else {
+                    module.names.add(&format!("{}{}", port, k))
+                };
+                pins.insert((port.clone(), k), pin.clone());
+                match value.get("direction").and_then(Value::as_str) {
+                    Some("input") => {
+                        module.ast.push(AST::IN(pin.clone()));
+                        chip.ins.push(pin.clone());
+                        module.driver(*bit, Driver::Pin(pin));
+                    }
+                    Some("output") => {
+                        module.ast.push(AST::OUT(pin.clone()));
+                        chip.outs.push(pin.clone());
+                        module.readers.entry(*bit).or_default().push(pin);
+                    }
+                    _ => return Err(self.invalid("Port", port, "has to be an input or an output")),
+                }
+            }
+        }
+        module.next = self.max_net(json) + 1;
+
+        let cells = json
+            .get("cells")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        for (name, cell) in cells {
+            self.cell(name, cell, &mut module, program)?;
+        }
+
+        let mut net_names = HashMap::new();
+        let netnames = json
+            .get("netnames")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        for (name, net) in netnames {
+            if net.get("hide_name").and_then(Value::as_u64) == Some(1) {
+                continue;
+            }
+            let bits = net
+                .get("bits")
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default();
+            for (k, bit) in bits.iter().enumerate() {
+                if let Some(bit) = Bit::from_json(bit) {
+                    let name = if bits.len() == 1 {
+                        name.clone()
+                    } else {
+                        format!("{}{}", name, k)
+                    };
+                    net_names.entry(bit).or_insert(name);
+                }
+            }
+        }
+
+        let mut rails = Vec::new();
+        let mut connections = Vec::new();
+        // RAIL without drivers, for the readers of a 0 or of nets nothing drives
+        let mut zero = None;
+        let mut readers = module.readers.clone().into_iter().collect::<Vec<_>>();
+        readers.sort();
+        for (bit, readers) in readers {
+            let sources = module.sources(bit, &mut Vec::new(), program);
+            let source = if sources.is_empty() {
+                zero.get_or_insert_with(|| {
+                    let rail = module.names.add("zero");
+                    rails.push(AST::RAIL(rail.clone()));
+                    rail
+                })
+                .clone()
+            } else if let [source] = sources.as_slice() {
+                source.clone()
+            } else {
+                let name = net_names.get(&bit).map(String::as_str).unwrap_or("or");
+                let rail = module.names.add(name);
+                rails.push(AST::RAIL(rail.clone()));
+                for source in sources {
+                    connections.push(AST::CONNECT(rail.clone(), source));
+                }
+                rail
+            };
+            for reader in readers {
+                connections.push(AST::CONNECT(reader, source.clone()));
+            }
+        }
+
+        let mut uses = module
+            .uses
+            .iter()
+            .map(|(path, alias)| AST::USE(path.clone(), alias.clone()))
+            .collect::<Vec<_>>();
+        uses.sort_by_key(|u| format!("{:?}", u));
+        chip.ast = uses;
+        chip.ast.append(&mut module.ast);
+        chip.ast.append(&mut rails);
+        chip.ast.append(&mut module.chips);
+        chip.ast.append(&mut connections);
+        self.imported.insert(
+            key.into(),
+            Imported {
+                chip: chip.name.clone(),
+                pins,
+            },
+        );
+        Ok(chip)
+    }
+
+    /// The highest net id in the module, so new ones can be made for the cells split in two
+    fn max_net(&self, json: &Value) -> u64 {
+        let mut max = 1;
+        let mut visit = |bits: Option<&Value>| {
+            for bit in bits.and_then(Value::as_array).into_iter().flatten() {
+                if let Some(Bit::Net(n)) = Bit::from_json(bit) {
+                    max = max.max(n);
+                }
+            }
+        };
+        for kind in &["ports", "netnames"] {
+            for (_, v) in json
+                .get(kind)
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+            {
+                visit(v.get("bits"));
+            }
+        }
+        for (_, cell) in json
+            .get("cells")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            for (_, v) in cell
+                .get("connections")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+            {
+                visit(Some(v));
+            }
+        }
+        max
+    }
+
+    fn bits(
+        &self,
+        value: Option<&Value>,
+        what: &str,
+        name: &str,
+    ) -> Result<Vec<Bit>, CompileError> {
+        value
+            .and_then(Value::as_array)
+            .and_then(|bits| bits.iter().map(Bit::from_json).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| self.invalid(what, name, "has invalid bits"))
+    }
+
+    fn cell(
+        &mut self,
+        name: &str,
+        cell: &Value,
+        module: &mut Module,
+        program: &mut Program,
+    ) -> Result<(), CompileError> {
+        let kind = cell.get("type").and_then(Value::as_str).unwrap_or_default();
+        let empty = Map::new();
+        let connections = cell
+            .get("connections")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        let pin = |pin: &str| -> Result<Bit, CompileError> {
+            match self.bits(connections.get(pin), "Cell", name)?.as_slice() {
+                [bit] => Ok(*bit),
+                _ => Err(self.error(
+                    ErrorKind::UnknownCell(format!("{} with {} wider than 1 bit", kind, pin)),
+                    self.find(name),
+                )),
+            }
+        };
+        // Yosys names its own cells with a $, those are named after their gate instead
+        let hint = |gate: &str| {
+            if name.starts_with('$') {
+                gate.to_lowercase()
+            } else {
+                name.to_string()
+            }
+        };
+        let gate = kind
+            .trim_start_matches("$_")
+            .trim_start_matches('$')
+            .trim_end_matches('_')
+            .to_uppercase();
+        match gate.as_str() {
+            "NOT" | "LOGIC_NOT" if kind.starts_with('$') => {
+                module.std(&hint("not"), "STD.NOT", &[pin("A")?], pin("Y")?, program)
+            }
+            "AND" | "LOGIC_AND" | "NAND" | "XOR" if kind.starts_with('$') => {
+                let std = match gate.as_str() {
+                    "NAND" => "STD.NAND",
+                    "XOR" => "STD.XOR",
+                    _ => "STD.AND",
+                };
+                module.std(
+                    &hint(&gate),
+                    std,
+                    &[pin("A")?, pin("B")?],
+                    pin("Y")?,
+                    program,
+                )
+            }
+            "OR" | "LOGIC_OR" if kind.starts_with('$') => {
+                module.driver(pin("Y")?, Driver::Or(vec![pin("A")?, pin("B")?]))
+            }
+            "BUF" | "POS" if kind.starts_with('$') => {
+                module.driver(pin("Y")?, Driver::Or(vec![pin("A")?]))
+            }
+            // Negated gates drive a new net which goes into a NOT
+            "NOR" | "XNOR" if kind.starts_with('$') => {
+                let net = Bit::Net(module.next);
+                module.next += 1;
+                if gate == "NOR" {
+                    module.driver(net, Driver::Or(vec![pin("A")?, pin("B")?]));
+                } else {
+                    module.std(
+                        &hint("xor"),
+                        "STD.XOR",
+                        &[pin("A")?, pin("B")?],
+                        net,
+                        program,
+                    );
+                }
+                module.std(&hint("not"), "STD.NOT", &[net], pin("Y")?, program)
+            }
+            _ if self.modules.contains_key(kind) => {
+                let imported = self.submodule(kind, program)?;
+                let mut ins = Vec::new();
+                let mut outs = Vec::new();
+                for (port, value) in connections {
+                    for (k, bit) in self
+                        .bits(Some(value), "Cell", name)?
+                        .into_iter()
+                        .enumerate()
+                    {
+                        let pin = imported.pins.get(&(port.clone(), k)).ok_or_else(|| {
+                            self.invalid(
+                                "Cell",
+                                name,
+                                &format!("connects {}, which isn't a port of {}", port, kind),
+                            )
+                        })?;
+                        let chip = program.get_chip(&imported.chip);
+                        if chip.ins.contains(pin) {
+                            ins.push((pin.clone(), bit));
+                        } else {
+                            outs.push((pin.clone(), bit));
+                        }
+                    }
+                }
+                module.instance(&hint(&imported.chip), &imported.chip, ins, outs);
+            }
+            _ => {
+                return Err(self.error(ErrorKind::UnknownCell(kind.into()), self.find(name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports the module `key` if it isn't yet, adding it to the program
+    fn submodule(&mut self, key: &str, program: &mut Program) -> Result<Imported, CompileError> {
+        if let Some(imported) = self.imported.get(key) {
+            return Ok(imported.clone());
+        }
+        // The yosys backend writes the STD chips as modules like STD_NOT, they're those chips again
+        let std = program
+            .files
+            .values()
+            .find(|c| c.name.starts_with("STD.") && c.name.replace('.', "_") == key);
+        if let Some(chip) = std {
+            let pins = chip
+                .ins
+                .iter()
+                .chain(&chip.outs)
+                .map(|p| ((p.clone(), 0), p.clone()))
+                .collect();
+            return Ok(Imported {
+                chip: chip.name.clone(),
+                pins,
+            });
+        }
+        if self.loading.iter().any(|l| l == key) {
+            let mut chain = self.loading.clone();
+            chain.push(key.into());
+            return Err(self.error(ErrorKind::CircularImport(chain), self.find(key)));
+        }
+        self.loading.push(key.into());
+        let name = self.chips.add(key);
+        let chip = self.module(key, name.clone(), program);
+        self.loading.pop();
+        program.files.insert(name, chip?);
+        Ok(self.imported[key].clone())
+    }
+}
+
+/// The statements of a chip being imported, and where every net gets its value
+struct Module {
+    names: Names,
+    /// Alias of every chip used, by path
+    uses: HashMap<String, String>,
+    /// INs and OUTs
+    ast: Vec<AST>,
+    chips: Vec<AST>,
+    drivers: HashMap<Bit, Vec<Driver>>,
+    readers: HashMap<Bit, Vec<String>>,
+    /// Next unused net id
+    next: u64,
+    /// Output of the NOT used as the constant 1
+    one: Option<String>,
+}
+
+impl Module {
+    fn driver(&mut self, bit: Bit, driver: Driver) {
+        self.drivers.entry(bit).or_default().push(driver);
+    }
+
+    /// Adds a sub-chip, reading and driving the given nets
+    fn instance(
+        &mut self,
+        name: &str,
+        path: &str,
+        ins: Vec<(String, Bit)>,
+        outs: Vec<(String, Bit)>,
+    ) {
+        let alias = match self.uses.get(path) {
+            Some(alias) => alias.clone(),
+            None => {
+                let alias = self.names.add(path.rsplit('.').next().unwrap());
+                self.uses.insert(path.into(), alias.clone());
+                alias
+            }
+        };
+        let name = self.names.add(name);
+        self.chips.push(AST::CHIP(alias, name.clone()));
+        for (pin, bit) in ins {
+            self.readers
+                .entry(bit)
+                .or_default()
+                .push(format!("{}.{}", name, pin));
+        }
+        for (pin, bit) in outs {
+            self.driver(bit, Driver::Pin(format!("{}.{}", name, pin)));
+        }
+    }
+
+    /// Adds one of the STD gates, with its pins in the same order as the cell ones
+    fn std(&mut self, name: &str, path: &str, ins: &[Bit], out: Bit, program: &Program) {
+        let chip = program.get_chip(&path.to_string());
+        let ins = chip.ins.iter().cloned().zip(ins.iter().copied()).collect();
+        self.instance(name, path, ins, vec![(chip.outs[0].clone(), out)]);
+    }
+
+    /// The INs and sub-chip outputs ORed into `bit`
+    fn sources(&mut self, bit: Bit, visiting: &mut Vec<Bit>, program: &Program) -> Vec<String> {
+        match bit {
+            Bit::Zero => Vec::new(),
+            // A NOT with nothing connected outputs 1
+            Bit::One => {
+                if self.one.is_none() {
+                    let not = program.get_chip(&"STD.NOT".to_string());
+                    let out = not.outs[0].clone();
+                    let net = Bit::Net(self.next);
+                    self.next += 1;
+                    self.instance("one", "STD.NOT", Vec::new(), vec![(out, net)]);
+                    self.one = self.drivers[&net].iter().find_map(|d| match d {
+                        Driver::Pin(p) => Some(p.clone()),
+                        _ => None,
+                    });
+                }
+                self.one.iter().cloned().collect()
+            }
+            Bit::Net(_) if visiting.contains(&bit) => Vec::new(),
+            Bit::Net(_) => {
+                visiting.push(bit);
+                let mut sources = Vec::new();
+                for driver in self.drivers.get(&bit).cloned().unwrap_or_default() {
+                    let found = match driver {
+                        Driver::Pin(pin) => vec![pin],
+                        Driver::Or(bits) => bits
+                            .into_iter()
+                            .flat_map(|b| self.sources(b, visiting, program))
+                            .collect(),
+                    };
+                    for source in found {
+                        if !sources.contains(&source) {
+                            sources.push(source);
+                        }
+                    }
+                }
+                visiting.pop();
+                sources
+            }
+        }
+    }
+}
+
+/// Byte offset in `src` of a serde_json error position, whose line counts `\n`s and whose
+/// column counts bytes, moved back to the start of the character it falls in
+fn json_offset(src: &str, line: usize, column: usize) -> usize {
+    let line_start = match line.checked_sub(2) {
+        Some(n) => src
+            .match_indices('\n')
+            .nth(n)
+            .map_or(src.len(), |(i, _)| i + 1),
+        None => 0,
+    };
+    let mut offset = (line_start + column.saturating_sub(1)).min(src.len());
+    while !src.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, YosysBackend};
+    use crate::lexer::parse_chip;
+    use crate::sim::Sim;
+
+    /// `json` imported as the chip `name`, from a file in the temporary directory
+    fn import_json(name: &str, json: &str) -> (Chip, Program) {
+        let path = std::env::temp_dir().join(format!("chip-test-{}.json", name));
+        fs::write(&path, json).unwrap();
+        let mut program = Program::new();
+        let chip = import(name.into(), &path, &mut program).unwrap();
+        fs::remove_file(&path).unwrap();
+        (chip, program)
+    }
+
+    fn outs(chip: &Chip, program: &Program, ins: &[bool]) -> Vec<bool> {
+        Sim::new(chip, program).unwrap().run(ins).unwrap()
+    }
+
+    #[test]
+    fn exported_chips_import_back_the_same() {
+        let (chip, program) = parse_chip("xor", include_str!("xor.chip"));
+        let json = YosysBackend::compile(chip.clone(), program.clone()).unwrap();
+        let (imported, imported_program) = import_json("xor", &json);
+        assert_eq!(imported.ins, chip.ins);
+        assert_eq!(imported.outs, chip.outs);
+        for ins in &[[false, false], [false, true], [true, false], [true, true]] {
+            assert_eq!(
+                outs(&imported, &imported_program, ins),
+                outs(&chip, &program, ins)
+            );
+        }
+    }
+
+    #[test]
+    fn constant_outputs_are_driven() {
+        let (chip, program) = import_json(
+            "constant",
+            r#"{"modules": {"constant": {"ports": {
+                "a": {"direction": "input", "bits": [2]},
+                "z": {"direction": "output", "bits": ["0"]},
+                "y": {"direction": "output", "bits": ["1"]}
+            }}}}"#,
+        );
+        let statements = format!("{:?}", chip.ast);
+        assert!(statements.contains("RAIL(\"zero\")"));
+        assert!(statements.contains("CONNECT(\"z\", \"zero\")"));
+        for a in &[false, true] {
+            assert_eq!(outs(&chip, &program, &[*a]), vec![false, true]);
+        }
+    }
+
+    #[test]
+    fn names_start_with_a_letter() {
+        let (chip, program) = import_json(
+            "digits",
+            r#"{"modules": {
+                "4bit": {"ports": {
+                    "1q": {"direction": "input", "bits": [2]},
+                    "2": {"direction": "output", "bits": [2]}
+                }},
+                "top": {"attributes": {"top": "1"}, "ports": {
+                    "a": {"direction": "input", "bits": [2]},
+                    "b": {"direction": "output", "bits": [3]}
+                }, "cells": {
+                    "1st": {"type": "4bit", "connections": {"1q": [2], "2": [3]}}
+                }}
+            }}"#,
+        );
+        let sub = &program.files["n4bit"];
+        assert_eq!(sub.ins, vec!["n1q".to_string()]);
+        assert_eq!(sub.outs, vec!["n2".to_string()]);
+        assert!(format!("{:?}", chip.ast).contains("CHIP(\"n4bit\", \"n1st\")"));
+        assert_eq!(outs(&chip, &program, &[true]), vec![true]);
+    }
+
+    #[test]
+    fn json_offsets_count_bytes() {
+        let src = "{\r\n  \"é\": x\r\n}";
+        let err = serde_json::from_str::<Value>(src).unwrap_err();
+        let offset = json_offset(src, err.line(), err.column());
+        assert_eq!(&src[offset..offset + 1], "x");
+        let span = Span {
+            start: offset,
+            end: offset,
+        };
+        assert_eq!(span.line_col(src), (2, 8));
+        // A column inside a multibyte character goes back to its start
+        assert_eq!(json_offset("\"é\"", 1, 3), 1);
+        assert_eq!(json_offset("{}", 5, 1), 2);
+    }
+}
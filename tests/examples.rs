@@ -0,0 +1,45 @@
+use std::fs;
+use std::process::{Command, Output};
+
+fn chip(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_chip"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn example_chips_pass_their_tests() {
+    for file in &[
+        "src/and.chip",
+        "src/nand.chip",
+        "src/xor.chip",
+        "src/test.chip",
+        "src/example.chip",
+    ] {
+        let output = chip(&["test", file]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.status.success(),
+            "{} failed:\n{}{}",
+            file,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(!stdout.contains(" 0 passed"), "{}:\n{}", file, stdout);
+    }
+}
+
+#[test]
+fn failed_expects_exit_with_an_error() {
+    let dir = std::env::temp_dir().join(format!("chip-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("wire.chip");
+    fs::write(&file, "IN a\nOUT b\nCONNECT a b\nTEST\nEXPECT a=1 => b=0\n").unwrap();
+    let output = chip(&["test", file.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("wire: 0 passed, 1 failed"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("b is 1, expected 0"));
+}